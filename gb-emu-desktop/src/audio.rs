@@ -10,10 +10,26 @@ use gbemu::{
   SAMPLE_RATE,
 };
 
+// How many bytes may be queued before the emulation waits for the audio to play.
+const DEFAULT_MAX_QUEUED_BYTES: u32 = 8192;
+// One buffer of interleaved stereo f32 samples from the APU.
+const BUFFER_BYTES: u32 = SAMPLES as u32 * 2 * 4;
+
+fn should_wait(queued_bytes: u32, max_bytes: u32) -> bool {
+  queued_bytes > max_bytes
+}
+
 pub struct Audio(pub Box<dyn Fn(&[f32])>);
 
 impl Audio {
   pub fn new(sdl: &Sdl) -> Audio {
+    Self::new_with_buffer(sdl, DEFAULT_MAX_QUEUED_BYTES)
+  }
+  // Lower values reduce the latency, but the audio may underrun.
+  pub fn new_with_buffer(sdl: &Sdl, max_bytes: u32) -> Audio {
+    if max_bytes < BUFFER_BYTES {
+      eprintln!("Warning: an audio buffer of less than {} bytes is likely to underrun.", BUFFER_BYTES);
+    }
     let audio = sdl
       .audio()
       .expect("failed to initialize SDL audio subsystem");
@@ -27,7 +43,7 @@ impl Audio {
     audio_queue.resume();
     Self(
       Box::new(move |buffer| {
-        while audio_queue.size() > 8192 {
+        while should_wait(audio_queue.size(), max_bytes) {
             std::thread::sleep(time::Duration::from_millis(1));
         }
         audio_queue.queue_audio(buffer).unwrap();
@@ -35,3 +51,18 @@ impl Audio {
     )
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn waits_only_above_the_threshold() {
+    assert!(!should_wait(0, DEFAULT_MAX_QUEUED_BYTES));
+    assert!(!should_wait(DEFAULT_MAX_QUEUED_BYTES, DEFAULT_MAX_QUEUED_BYTES));
+    assert!(should_wait(DEFAULT_MAX_QUEUED_BYTES + 1, DEFAULT_MAX_QUEUED_BYTES));
+    // A smaller threshold waits sooner.
+    assert!(should_wait(BUFFER_BYTES + 1, BUFFER_BYTES));
+    assert!(!should_wait(BUFFER_BYTES + 1, DEFAULT_MAX_QUEUED_BYTES));
+  }
+}
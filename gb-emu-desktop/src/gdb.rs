@@ -0,0 +1,159 @@
+// A minimal GDB remote serial protocol server.
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+//
+// Registers are sent in the order A, F, B, C, D, E, H, L as bytes, then SP and PC as little-endian words.
+use std::{
+  collections::HashSet,
+  io::{self, Read, Write},
+  net::{TcpListener, TcpStream},
+};
+
+use gbemu::gameboy::GameBoy;
+
+pub struct GdbStub {
+  stream: TcpStream,
+  breakpoints: HashSet<u16>,
+  running: bool,
+  stepping: bool,
+  // Set by Ctrl-C, so that `serve` reports the stop even though `running` was cleared.
+  interrupted: bool,
+}
+
+fn hex(data: &[u8]) -> String {
+  data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+  (0..s.len() / 2).map(|i| u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()).collect()
+}
+
+// Parses "addr,len" of `m`/`M` and "type,addr,kind" of `Z`/`z`.
+fn parse_args(s: &str) -> Vec<usize> {
+  s.split(',').filter_map(|v| usize::from_str_radix(v, 16).ok()).collect()
+}
+
+impl GdbStub {
+  // Blocks until a debugger connects. The machine starts stopped.
+  pub fn listen(port: u16) -> io::Result<Self> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Waiting for GDB on port {}.", port);
+    let (stream, addr) = listener.accept()?;
+    println!("GDB connected from {}.", addr);
+    Ok(Self {
+      stream,
+      breakpoints: HashSet::new(),
+      running: false,
+      stepping: false,
+      interrupted: false,
+    })
+  }
+
+  // Called at every instruction boundary.
+  pub fn should_stop(&self, pc: u16) -> bool {
+    !self.running || self.stepping || self.breakpoints.contains(&pc)
+  }
+
+  // Checks for Ctrl-C from the debugger without blocking.
+  pub fn poll_interrupt(&mut self) {
+    let mut buf = [0; 1];
+    if self.stream.set_nonblocking(true).is_err() {
+      return;
+    }
+    if let Ok(1) = self.stream.peek(&mut buf) {
+      if buf[0] == 0x03 {
+        let _ = self.stream.read(&mut buf);
+        self.running = false;
+        self.interrupted = true;
+      }
+    }
+    let _ = self.stream.set_nonblocking(false);
+  }
+
+  // Serves requests while the machine is stopped. Returns when the debugger resumes it, or an error when it disconnects.
+  pub fn serve(&mut self, gameboy: &mut GameBoy) -> io::Result<()> {
+    if self.running || self.interrupted {
+      self.interrupted = false;
+      self.send("S05")?;
+    }
+    loop {
+      let packet = self.recv()?;
+      let cmd = packet.get(..1).unwrap_or("");
+      let args = packet.get(1..).unwrap_or("");
+      let reply = match cmd {
+        "?" => String::from("S05"),
+        "g" => {
+          let regs = gameboy.cpu.registers();
+          let mut data = vec![regs.a, regs.f, regs.b, regs.c, regs.d, regs.e, regs.h, regs.l];
+          data.extend_from_slice(&regs.sp.to_le_bytes());
+          data.extend_from_slice(&regs.pc.to_le_bytes());
+          hex(&data)
+        },
+        "m" => match parse_args(args)[..] {
+          [addr, len] => {
+            let data: Vec<u8> = (0..len).map(|i| gameboy.peek((addr + i) as u16)).collect();
+            hex(&data)
+          },
+          _ => String::from("E01"),
+        },
+        "M" => match args.split_once(':').map(|(a, d)| (parse_args(a), unhex(d))) {
+          Some((a, Some(data))) if a.len() == 2 => {
+            for (i, &b) in data.iter().enumerate() {
+              gameboy.poke((a[0] + i) as u16, b);
+            }
+            String::from("OK")
+          },
+          _ => String::from("E01"),
+        },
+        "Z" | "z" => match parse_args(args)[..] {
+          [0 | 1, addr, _] => {
+            if cmd == "Z" {
+              self.breakpoints.insert(addr as u16);
+            } else {
+              self.breakpoints.remove(&(addr as u16));
+            }
+            String::from("OK")
+          },
+          _ => String::new(),
+        },
+        "c" | "s" => {
+          self.running = true;
+          self.stepping = cmd == "s";
+          return Ok(());
+        },
+        "k" => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "killed by GDB")),
+        _ => String::new(),
+      };
+      self.send(&reply)?;
+    }
+  }
+
+  fn send(&mut self, data: &str) -> io::Result<()> {
+    let sum = data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+    write!(self.stream, "${}#{:02x}", data, sum)?;
+    self.stream.flush()
+  }
+
+  // Reads the next packet, skipping acks and interrupts, and acknowledges it.
+  fn recv(&mut self) -> io::Result<String> {
+    let mut byte = [0; 1];
+    loop {
+      self.stream.read_exact(&mut byte)?;
+      if byte[0] == b'$' {
+        break;
+      }
+    }
+    let mut data = vec![];
+    loop {
+      self.stream.read_exact(&mut byte)?;
+      if byte[0] == b'#' {
+        break;
+      }
+      data.push(byte[0]);
+    }
+    let mut sum = [0; 2];
+    self.stream.read_exact(&mut sum)?;
+    self.stream.write_all(b"+")?;
+    Ok(String::from_utf8_lossy(&data).into_owned())
+  }
+}
+
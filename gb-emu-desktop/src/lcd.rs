@@ -1,5 +1,6 @@
 use sdl2::{
-  pixels::PixelFormatEnum,
+  pixels::{Color, PixelFormatEnum},
+  rect::Rect,
   render::Canvas,
   video::Window,
   Sdl,
@@ -7,7 +8,54 @@ use sdl2::{
 
 use gbemu::{LCD_WIDTH, LCD_HEIGHT};
 
-pub struct LCD(Canvas<Window>);
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+  Nearest,
+  Scale2x,
+}
+
+// https://www.scale2x.it/algorithm
+fn scale2x(src: &[u8], w: usize, h: usize) -> Vec<u8> {
+  let px = |x: usize, y: usize| -> [u8; 4] {
+    let i = (y * w + x) * 4;
+    src[i..i + 4].try_into().unwrap()
+  };
+  let mut dst = vec![0; w * h * 16];
+  for y in 0..h {
+    for x in 0..w {
+      let e = px(x, y);
+      let b = px(x, y.saturating_sub(1));
+      let d = px(x.saturating_sub(1), y);
+      let f = px(std::cmp::min(x + 1, w - 1), y);
+      let h2 = px(x, std::cmp::min(y + 1, h - 1));
+      let out = if b != h2 && d != f {
+        [
+          if d == b { d } else { e },
+          if b == f { f } else { e },
+          if d == h2 { d } else { e },
+          if h2 == f { f } else { e },
+        ]
+      } else {
+        [e; 4]
+      };
+      for (k, p) in out.iter().enumerate() {
+        let i = ((y * 2 + k / 2) * w * 2 + x * 2 + k % 2) * 4;
+        dst[i..i + 4].copy_from_slice(p);
+      }
+    }
+  }
+  dst
+}
+
+// The margin around the screen in LCD pixels.
+const BORDER: u32 = 8;
+
+// The size of the screen including the border, in LCD pixels.
+fn bordered_size() -> (u32, u32) {
+  (LCD_WIDTH as u32 + BORDER * 2, LCD_HEIGHT as u32 + BORDER * 2)
+}
+
+pub struct LCD(Canvas<Window>, Filter, Option<Color>);
 
 impl LCD {
   pub fn new(sdl: &Sdl, scale: u32) -> LCD {
@@ -18,20 +66,83 @@ impl LCD {
       .build()
       .expect("failed to create a window");
     let canvas = window.into_canvas().build().unwrap();
-    Self(canvas)
+    Self(canvas, Filter::Nearest, None)
+  }
+  // Draws the screen centered in a margin of `border_color`.
+  pub fn new_with_border(sdl: &Sdl, scale: u32, border_color: Color) -> LCD {
+    let (width, height) = bordered_size();
+    let window = sdl.video().expect("failed to initialize SDL video subsystem")
+      .window("gb-emu", width * scale, height * scale)
+      .position_centered()
+      .resizable()
+      .build()
+      .expect("failed to create a window");
+    let mut canvas = window.into_canvas().build().unwrap();
+    canvas.set_logical_size(width, height).unwrap();
+    canvas.set_draw_color(border_color);
+    Self(canvas, Filter::Nearest, Some(border_color))
+  }
+  pub fn set_filter(&mut self, filter: Filter) {
+    self.1 = filter;
   }
   pub fn draw(&mut self, pixels: &Vec<u8>) {
+    let scaled;
+    let (pixels, scale) = match self.1 {
+      Filter::Nearest => (pixels, 1),
+      Filter::Scale2x => {
+        scaled = scale2x(pixels, LCD_WIDTH, LCD_HEIGHT);
+        (&scaled, 2)
+      },
+    };
     let texture_creator = self.0.texture_creator();
     let mut texture = texture_creator
-      .create_texture_streaming(PixelFormatEnum::RGBA32, LCD_WIDTH as u32, LCD_HEIGHT as u32)
+      .create_texture_streaming(PixelFormatEnum::RGBA32, LCD_WIDTH as u32 * scale, LCD_HEIGHT as u32 * scale)
       .unwrap();
 
-    texture.update(None, pixels, 640).unwrap();
+    texture.update(None, pixels, LCD_WIDTH * scale as usize * 4).unwrap();
     self.0.clear();
-    self.0.copy(&texture, None, None).unwrap();
+    let dst = self.2.map(|_| Rect::new(BORDER as i32, BORDER as i32, LCD_WIDTH as u32, LCD_HEIGHT as u32));
+    self.0.copy(&texture, None, dst).unwrap();
     self.0.present();
   }
   pub fn resize(&mut self, width: u32, _: u32) {
+    if self.2.is_some() {
+      // The logical size is in LCD pixels, so the border keeps its proportion.
+      let (width, height) = bordered_size();
+      self.0.set_logical_size(width, height).unwrap();
+      return;
+    }
     self.0.set_logical_size(width, width * LCD_HEIGHT as u32 / LCD_WIDTH as u32).unwrap();
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn scale2x_smooths_a_diagonal() {
+    let rgba = |rows: &[&str]| -> Vec<u8> {
+      rows.iter().flat_map(|row| row.bytes()).flat_map(|c| [c; 4]).collect()
+    };
+    let src = rgba(&[
+      "XO",
+      "OX",
+    ]);
+    assert_eq!(scale2x(&src, 2, 2), rgba(&[
+      "XXOO",
+      "XOXO",
+      "OXOX",
+      "OOXX",
+    ]));
+    // Flat areas are only doubled.
+    assert_eq!(scale2x(&rgba(&["OO", "OO"]), 2, 2), rgba(&["OOOO"; 4]));
+  }
+  #[test]
+  fn border_surrounds_the_screen() {
+    // 8 LCD pixels on each side, so that the screen is centered at (BORDER, BORDER).
+    assert_eq!(bordered_size(), (176, 160));
+    let (width, height) = bordered_size();
+    assert_eq!(((width - LCD_WIDTH as u32) / 2, (height - LCD_HEIGHT as u32) / 2), (BORDER, BORDER));
+  }
+}
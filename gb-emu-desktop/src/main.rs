@@ -1,7 +1,8 @@
 use std::{
+  cell::{Cell, RefCell},
   env,
   fs::File,
-  io::{ Read, Write, },
+  io::{ BufWriter, Read, Write, },
   process::exit,
   rc::Rc,
   time,
@@ -10,27 +11,42 @@ use std::{
 use sdl2::{
   event::{Event, WindowEvent},
   keyboard::Keycode,
+  pixels::Color,
   Sdl,
 };
 
 use gbemu::{
   gameboy,
   joypad,
+  printer::Printer,
+  Cartridge,
+  Layer,
+  SerialDevice,
+  CPU_CLOCK_HZ,
 };
 
 use crate::{
   gameboy::GameBoy,
-  lcd::LCD,
+  lcd::{Filter, LCD},
   joypad::Button,
-  audio::Audio
+  audio::Audio,
+  gdb::GdbStub,
+  wav::WavWriter,
 };
 
 mod lcd;
 mod audio;
+mod gdb;
+mod wav;
 
-const CPU_CLOCK_HZ: u128 = 4_194_304;
 const M_CYCLE_CLOCK: u128 = 4;
-const M_CYCLE_NANOS: u128 = M_CYCLE_CLOCK * 1_000_000_000 / CPU_CLOCK_HZ;
+// A frame is 154 lines of 456 dots, which is 16.742 ms at the standard clock (59.7275 Hz).
+const DOTS_PER_FRAME: u128 = 154 * 456;
+const GAIN_STEP: f32 = 0.1;
+
+fn frame_nanos(clock_hz: u128) -> u128 {
+  DOTS_PER_FRAME * 1_000_000_000 / clock_hz
+}
 
 fn key2joy(keycode: Keycode) -> Option<Button> {
   match keycode {
@@ -46,23 +62,144 @@ fn key2joy(keycode: Keycode) -> Option<Button> {
   }
 }
 
+fn key2layer(keycode: Keycode) -> Option<Layer> {
+  match keycode {
+    Keycode::F1 => Some(Layer::Background),
+    Keycode::F2 => Some(Layer::Window),
+    Keycode::F3 => Some(Layer::Sprites),
+    _ => None,
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Pace {
+  // Keeps every M-cycle on schedule.
+  Cycle,
+  // Runs each frame at full speed, then sleeps until the next one is due.
+  Frame,
+}
+
 pub struct Emulator {
   gameboy: GameBoy,
   lcd: LCD,
   sdl: Sdl,
+  m_cycle_nanos: u128,
+  frame_nanos: u128,
+  pace: Pace,
+  trace: Option<BufWriter<File>>,
+  gdb: Option<GdbStub>,
+  serial_device: Option<Box<dyn SerialDevice>>,
 }
 
 impl Emulator {
-  pub fn new(cart_rom: &[u8], save: &[u8]) -> Self {
-    let mut gameboy = GameBoy::new(cart_rom, save);
+  // Save states and link play assume the standard clock; `clock_hz` only changes the pacing.
+  // `lenient` loads ROMs with a wrong header checksum or an unknown cartridge type.
+  pub fn new(cart_rom: &[u8], save: &[u8], clock_hz: u128, border: Option<Color>, lenient: bool) -> Self {
+    let gameboy = if lenient {
+      let save = if save.is_empty() { None } else { Some(save.to_vec()) };
+      Cartridge::new_lenient(cart_rom.into(), save).map(GameBoy::from_cartridge).map_err(|e| e.to_string())
+    } else {
+      GameBoy::try_new(cart_rom, save)
+    };
+    let mut gameboy = gameboy.unwrap_or_else(|e| {
+      eprintln!("{}", e);
+      exit(1);
+    });
+    gameboy.peripherals.apu.set_clock_hz(clock_hz);
     let sdl = sdl2::init().expect("failed to initialize SDL");
-    let lcd = LCD::new(&sdl, 4);
+    let lcd = match border {
+      Some(color) => LCD::new_with_border(&sdl, 4, color),
+      None => LCD::new(&sdl, 4),
+    };
     let audio = Audio::new(&sdl);
     gameboy.peripherals.apu.set_callback(Rc::new(audio.0));
     Self {
       gameboy,
       lcd,
       sdl,
+      m_cycle_nanos: M_CYCLE_CLOCK * 1_000_000_000 / clock_hz,
+      frame_nanos: frame_nanos(clock_hz),
+      pace: Pace::Cycle,
+      trace: None,
+      gdb: None,
+      serial_device: None,
+    }
+  }
+
+  // Without a device, the link cable reads as disconnected.
+  pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) {
+    self.serial_device = Some(device);
+  }
+
+  // Replaces the audio output with one that queues up to `max_bytes`.
+  pub fn set_audio_buffer(&mut self, max_bytes: u32) {
+    let audio = Audio::new_with_buffer(&self.sdl, max_bytes);
+    self.gameboy.peripherals.apu.set_callback(Rc::new(audio.0));
+  }
+
+  pub fn set_mono(&mut self, on: bool) {
+    self.gameboy.peripherals.apu.set_mono(on);
+  }
+
+  pub fn set_pace(&mut self, pace: Pace) {
+    self.pace = pace;
+  }
+
+  pub fn set_filter(&mut self, filter: Filter) {
+    self.lcd.set_filter(filter);
+  }
+
+  // Writes the audio to `wav` as well as playing it. The file is finalized when the emulator is dropped.
+  pub fn record_wav(&mut self, wav: WavWriter) {
+    let wav = RefCell::new(wav);
+    let play = self.gameboy.peripherals.apu.callback.clone();
+    self.gameboy.peripherals.apu.set_callback(Rc::new(move |buffer| {
+      if let Err(e) = wav.borrow_mut().write(buffer) {
+        eprintln!("Cannot write the WAV file: {}", e);
+        exit(1);
+      }
+      if let Some(play) = play.as_ref() {
+        play(buffer);
+      }
+    }));
+  }
+
+  pub fn attach_gdb(&mut self, gdb: GdbStub) {
+    self.gdb = Some(gdb);
+  }
+
+  // Returns whether the machine stopped for the debugger. Detaches it when the connection is closed.
+  fn check_gdb(&mut self) -> bool {
+    let Some(gdb) = self.gdb.as_mut() else { return false };
+    if !gdb.should_stop(self.gameboy.cpu.registers().pc) {
+      return false;
+    }
+    if let Err(e) = gdb.serve(&mut self.gameboy) {
+      println!("GDB detached: {}", e);
+      self.gdb = None;
+    }
+    true
+  }
+
+  // Logs every instruction in the Gameboy Doctor format. The boot ROM is skipped so that the log starts at 0x0100.
+  pub fn trace_to(&mut self, file: File) {
+    self.gameboy.reset();
+    self.trace = Some(BufWriter::new(file));
+  }
+
+  fn write_trace(&mut self) {
+    let Some(trace) = self.trace.as_mut() else { return };
+    let regs = self.gameboy.cpu.registers();
+    // `peek` so that tracing doesn't trip read watches.
+    let mem: Vec<u8> = (0..4).map(|i| self.gameboy.peek(regs.pc.wrapping_add(i))).collect();
+    let line = writeln!(trace,
+      "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+      regs.a, regs.f, regs.b, regs.c, regs.d, regs.e, regs.h, regs.l, regs.sp, regs.pc,
+      mem[0], mem[1], mem[2], mem[3],
+    );
+    if line.is_err() {
+      eprintln!("Failed to write the trace log.");
+      self.trace = None;
     }
   }
 
@@ -70,9 +207,16 @@ impl Emulator {
     let mut event_pump = self.sdl.event_pump().unwrap();
     let time = time::Instant::now();
     let mut elapsed = 0;
+    let frame_duration = time::Duration::from_nanos(self.frame_nanos as u64);
+    let mut next_frame = time + frame_duration;
     'running: loop {
       let e = time.elapsed().as_nanos();
-      for _ in 0..(e - elapsed) / M_CYCLE_NANOS {
+      let cycles = match self.pace {
+        Pace::Cycle => (e - elapsed) / self.m_cycle_nanos,
+        // Frame pacing sleeps at each vblank instead.
+        Pace::Frame => u128::MAX,
+      };
+      for _ in 0..cycles {
         for event in event_pump.poll_iter() {
           match event {
             Event::Quit { .. } => break 'running,
@@ -80,29 +224,70 @@ impl Emulator {
 
             Event::KeyDown { keycode: Some(k), .. } => {
               if k == Keycode::Escape { break 'running }
-              key2joy(k).map(|j| self.gameboy.peripherals.joypad.button_down(&mut self.gameboy.cpu.interrupts, j));
+              if let Some(j) = key2joy(k) { self.gameboy.button_down(j) }
             },
             Event::KeyUp { keycode: Some(k), .. } => {
               if k == Keycode::Return { self.save_to_file() }
-              key2joy(k).map(|j| self.gameboy.peripherals.joypad.button_up(j));
+              if let Some(l) = key2layer(k) { self.toggle_layer(l) }
+              match k {
+                Keycode::Equals | Keycode::Plus | Keycode::KpPlus => self.change_gain(GAIN_STEP),
+                Keycode::Minus | Keycode::KpMinus => self.change_gain(-GAIN_STEP),
+                _ => (),
+              }
+              if let Some(j) = key2joy(k) { self.gameboy.button_up(j) }
             },
             _ => (),
           }
         }
         if self.gameboy.emulate_cycle() {
           self.lcd.draw(&self.gameboy.peripherals.ppu.buffer);
+          if let Some(gdb) = self.gdb.as_mut() {
+            gdb.poll_interrupt();
+          }
+          if self.pace == Pace::Frame {
+            let now = time::Instant::now();
+            if next_frame > now {
+              std::thread::sleep(next_frame - now);
+            } else if now - next_frame > frame_duration {
+              // Too far behind, e.g. after stopping in the debugger. Don't try to catch up.
+              next_frame = now;
+            }
+            next_frame += frame_duration;
+          }
         }
-        if self.gameboy.peripherals.serial.send().is_some() {
-          self.gameboy.peripherals.serial.recv(0xFF);
+        if self.gameboy.cpu.at_instruction_boundary() {
+          if self.trace.is_some() {
+            self.write_trace();
+          }
+          if self.check_gdb() {
+            // Don't try to catch up on the time spent stopped.
+            elapsed = time.elapsed().as_nanos();
+            continue 'running;
+          }
         }
-        elapsed += M_CYCLE_NANOS;
+        if let Some(data) = self.gameboy.peripherals.serial.send() {
+          let val = self.serial_device.as_mut().map_or(0xFF, |d| d.transfer(data));
+          self.gameboy.peripherals.serial.recv(val);
+        }
+        elapsed += self.m_cycle_nanos;
       }
     }
   }
 
+  fn toggle_layer(&mut self, layer: Layer) {
+    let on = !self.gameboy.peripherals.ppu.layer_enabled(layer);
+    self.gameboy.peripherals.ppu.set_layer_enabled(layer, on);
+  }
+
+  fn change_gain(&mut self, delta: f32) {
+    let gain = self.gameboy.peripherals.apu.master_gain() + delta;
+    self.gameboy.peripherals.apu.set_master_gain(gain);
+  }
+
   fn save_to_file(&self) {
-    if self.gameboy.peripherals.cartridge.sram.len() == 0 {
-      return eprintln!("The cartridge doesn't have ram.");
+    let cartridge = &self.gameboy.peripherals.cartridge;
+    if cartridge.sram_bytes().is_empty() || !cartridge.has_battery() {
+      return eprintln!("The cartridge doesn't have battery-backed ram.");
     }
     let fname = format!("{}.SAV", self.gameboy.peripherals.cartridge.title);
     let mut file = if let Ok(f) = File::create(&fname) {
@@ -110,7 +295,7 @@ impl Emulator {
     } else {
       return;
     };
-    if file.write_all(&self.gameboy.peripherals.cartridge.sram).is_err() {
+    if file.write_all(self.gameboy.peripherals.cartridge.sram_bytes()).is_err() {
       return eprintln!("Failed to save \"{}\"", fname);
     }
     if file.flush().is_err() {
@@ -120,6 +305,23 @@ impl Emulator {
   }
 }
 
+// Saves each printout as a PGM image.
+fn printer() -> Printer {
+  let mut printer = Printer::new();
+  let count = Cell::new(0);
+  printer.set_output(Box::new(move |pixels, width| {
+    count.set(count.get() + 1);
+    let fname = format!("print{}.pgm", count.get());
+    let mut data = format!("P5\n{} {}\n3\n", width, pixels.len() / width).into_bytes();
+    data.extend(pixels.iter().map(|&p| 3 - p));
+    match File::create(&fname).and_then(|mut f| f.write_all(&data)) {
+      Ok(_)  => println!("Printed \"{}\"", fname),
+      Err(_) => eprintln!("Failed to save \"{}\"", fname),
+    }
+  }));
+  printer
+}
+
 fn file2vec(fname: &String) -> Vec<u8> {
   if let Ok(mut file) = File::open(fname) {
     let mut ret = vec![];
@@ -130,8 +332,97 @@ fn file2vec(fname: &String) -> Vec<u8> {
   }
 }
 
+// Removes `name` and its value from `args`.
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+  let i = args.iter().position(|a| a == name)?;
+  if i + 1 >= args.len() {
+    eprintln!("{} requires a value.", name);
+    exit(1);
+  }
+  let val = args.remove(i + 1);
+  args.remove(i);
+  Some(val)
+}
+
+// Removes `name` from `args` and returns whether it was there.
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+  let Some(i) = args.iter().position(|a| a == name) else { return false };
+  args.remove(i);
+  true
+}
+
 fn main() {
-  let args: Vec<String> = env::args().collect();
+  env_logger::init();
+  let mut args: Vec<String> = env::args().collect();
+  let clock_hz = match take_option(&mut args, "--clock-hz").map(|v| v.parse()) {
+    None => CPU_CLOCK_HZ,
+    Some(Ok(hz)) if (M_CYCLE_CLOCK..=M_CYCLE_CLOCK * 1_000_000_000).contains(&hz) => hz,
+    Some(_) => {
+      eprintln!("--clock-hz requires a frequency in Hz.");
+      exit(1);
+    }
+  };
+  let trace = take_option(&mut args, "--trace").map(|fname| match File::create(&fname) {
+    Ok(f) => f,
+    Err(_) => {
+      eprintln!("Cannot create {}.", fname);
+      exit(1);
+    }
+  });
+  let filter = match take_option(&mut args, "--filter").as_deref() {
+    None | Some("nearest") => Filter::Nearest,
+    Some("scale2x") => Filter::Scale2x,
+    Some(_) => {
+      eprintln!("--filter must be nearest or scale2x.");
+      exit(1);
+    }
+  };
+  let wav = take_option(&mut args, "--wav").map(|fname| match File::create(&fname).and_then(WavWriter::new) {
+    Ok(w) => w,
+    Err(_) => {
+      eprintln!("Cannot create {}.", fname);
+      exit(1);
+    }
+  });
+  let mono = take_flag(&mut args, "--mono");
+  let no_checksum = take_flag(&mut args, "--no-checksum");
+  let audio_buffer = take_option(&mut args, "--audio-buffer").map(|v| match v.parse::<u32>() {
+    Ok(bytes) => bytes,
+    Err(_) => {
+      eprintln!("--audio-buffer requires a size in bytes.");
+      exit(1);
+    }
+  });
+  let pace = match take_option(&mut args, "--pace").as_deref() {
+    None | Some("cycle") => Pace::Cycle,
+    Some("frame") => Pace::Frame,
+    Some(_) => {
+      eprintln!("--pace must be frame or cycle.");
+      exit(1);
+    }
+  };
+  let border = take_option(&mut args, "--border").map(|v| match u32::from_str_radix(&v, 16) {
+    Ok(rgb) if v.len() == 6 => Color::RGB((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8),
+    _ => {
+      eprintln!("--border requires a color in RRGGBB.");
+      exit(1);
+    }
+  });
+  let serial_device: Option<Box<dyn SerialDevice>> = match take_option(&mut args, "--serial").as_deref() {
+    None => None,
+    Some("printer") => Some(Box::new(printer())),
+    Some(_) => {
+      eprintln!("--serial must be printer.");
+      exit(1);
+    }
+  };
+  let gdb_port = take_option(&mut args, "--gdb").map(|v| match v.parse::<u16>() {
+    Ok(port) => port,
+    Err(_) => {
+      eprintln!("--gdb requires a port number.");
+      exit(1);
+    }
+  });
   if args.len() < 2 {
     eprintln!("The file name argument is required.");
     exit(1);
@@ -139,6 +430,43 @@ fn main() {
   let cartridge_raw = file2vec(&args[1]);
   let save = if args.len() >= 3 { file2vec(&args[2]) } else { vec![] };
 
-  let mut emulator = Emulator::new(&cartridge_raw, &save);
+  let mut emulator = Emulator::new(&cartridge_raw, &save, clock_hz, border, no_checksum);
+  emulator.set_filter(filter);
+  emulator.set_pace(pace);
+  emulator.set_mono(mono);
+  if let Some(bytes) = audio_buffer {
+    emulator.set_audio_buffer(bytes);
+  }
+  if let Some(device) = serial_device {
+    emulator.connect_serial(device);
+  }
+  if let Some(wav) = wav {
+    emulator.record_wav(wav);
+  }
+  if let Some(file) = trace {
+    emulator.trace_to(file);
+  }
+  if let Some(port) = gdb_port {
+    match GdbStub::listen(port) {
+      Ok(gdb) => emulator.attach_gdb(gdb),
+      Err(e) => {
+        eprintln!("Cannot start the GDB server: {}", e);
+        exit(1);
+      }
+    }
+  }
   emulator.run();
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn frame_duration_at_59_7275_hz() {
+    assert_eq!(frame_nanos(CPU_CLOCK_HZ), 16_742_706);
+    assert!((1e9 / frame_nanos(CPU_CLOCK_HZ) as f64 - 59.7275).abs() < 1e-4);
+    // Overclocking shortens the frames in proportion.
+    assert_eq!(frame_nanos(CPU_CLOCK_HZ * 2), 16_742_706 / 2);
+  }
+}
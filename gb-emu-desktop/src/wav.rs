@@ -0,0 +1,95 @@
+// Writes the APU output as a 16-bit stereo PCM WAV file.
+use std::{
+  fs::File,
+  io::{self, BufWriter, Seek, SeekFrom, Write},
+};
+
+use gbemu::SAMPLE_RATE;
+
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+const HEADER_LEN: u32 = 44;
+
+pub struct WavWriter {
+  file: BufWriter<File>,
+  data_len: u32,
+}
+
+impl WavWriter {
+  pub fn new(file: File) -> io::Result<Self> {
+    let mut ret = Self {
+      file: BufWriter::new(file),
+      data_len: 0,
+    };
+    ret.write_header()?;
+    Ok(ret)
+  }
+
+  fn write_header(&mut self) -> io::Result<()> {
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    let f = &mut self.file;
+    f.write_all(b"RIFF")?;
+    f.write_all(&(HEADER_LEN - 8 + self.data_len).to_le_bytes())?;
+    f.write_all(b"WAVEfmt ")?;
+    f.write_all(&16u32.to_le_bytes())?;
+    // PCM
+    f.write_all(&1u16.to_le_bytes())?;
+    f.write_all(&CHANNELS.to_le_bytes())?;
+    f.write_all(&(SAMPLE_RATE as u32).to_le_bytes())?;
+    f.write_all(&(SAMPLE_RATE as u32 * block_align as u32).to_le_bytes())?;
+    f.write_all(&block_align.to_le_bytes())?;
+    f.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+    f.write_all(b"data")?;
+    f.write_all(&self.data_len.to_le_bytes())
+  }
+
+  // `buffer` holds interleaved left and right samples in -1.0..=1.0.
+  pub fn write(&mut self, buffer: &[f32]) -> io::Result<()> {
+    for &s in buffer {
+      let v = (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+      self.file.write_all(&v.to_le_bytes())?;
+    }
+    self.data_len += (buffer.len() * 2) as u32;
+    Ok(())
+  }
+
+  // Fills in the lengths, which are unknown until the recording ends.
+  pub fn finish(&mut self) -> io::Result<()> {
+    self.file.seek(SeekFrom::Start(0))?;
+    self.write_header()?;
+    self.file.seek(SeekFrom::End(0))?;
+    self.file.flush()
+  }
+}
+
+impl Drop for WavWriter {
+  fn drop(&mut self) {
+    let _ = self.finish();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn header_describes_the_samples() {
+    let path = std::env::temp_dir().join(format!("gb-emu-wav-test-{}.wav", std::process::id()));
+    {
+      let mut wav = WavWriter::new(File::create(&path).unwrap()).unwrap();
+      wav.write(&[0.0, 1.0, -1.0, 2.0]).unwrap();
+    }
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+    assert_eq!(bytes.len(), 44 + 8);
+    assert_eq!((&bytes[0..4], u32_at(4), &bytes[8..16]), (&b"RIFF"[..], 36 + 8, &b"WAVEfmt "[..]));
+    assert_eq!((u32_at(16), u16_at(20), u16_at(22)), (16, 1, 2));
+    assert_eq!((u32_at(24), u32_at(28), u16_at(32), u16_at(34)), (48000, 48000 * 4, 4, 16));
+    assert_eq!((&bytes[36..40], u32_at(40)), (&b"data"[..], 8));
+    // Out of range samples are clamped.
+    let samples: Vec<i16> = bytes[44..].chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+    assert_eq!(samples, [0, i16::MAX, -i16::MAX, i16::MAX]);
+  }
+}
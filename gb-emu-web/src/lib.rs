@@ -1,25 +1,40 @@
-use std::rc::Rc;
+use std::{borrow::Cow, rc::Rc};
 
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use js_sys::{Float32Array, Function, Uint8ClampedArray, Uint8Array};
 use rodio::{buffer::SamplesBuffer, OutputStream, OutputStreamHandle, Sink};
 use wasm_bindgen::prelude::*;
 
-// #[wasm_bindgen]
-// extern "C" {
-//   #[wasm_bindgen(js_namespace = console)]
-//   fn log(s: &str);
-// }
+#[wasm_bindgen]
+extern "C" {
+  // #[wasm_bindgen(js_namespace = console)]
+  // fn log(s: &str);
+  #[wasm_bindgen(js_namespace = console)]
+  fn warn(s: &str);
+}
 // macro_rules! console_log {
 //   ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
 // }
 
 use gbemu::{
-  gameboy::GameBoy,
+  gameboy::{GameBoy, STATE_VERSION},
   joypad::Button,
+  Layer,
+  LCD_WIDTH,
+  LCD_HEIGHT,
+  LCD_PIXELS,
   SAMPLE_RATE,
 };
 
+fn str2layer(layer: &str) -> Option<Layer> {
+  match layer {
+    "background" => Some(Layer::Background),
+    "window"     => Some(Layer::Window),
+    "sprites"    => Some(Layer::Sprites),
+    _            => None,
+  }
+}
+
 fn key2joy(keycode: &str) -> Option<Button> {
   match keycode {
     "KeyW"      => Some(Button::Up),
@@ -34,11 +49,88 @@ fn key2joy(keycode: &str) -> Option<Button> {
   }
 }
 
+// Returns [x, y, w, h] of the largest integer-scaled screen centered in the container.
+// Containers smaller than the LCD get the largest rectangle with the LCD aspect ratio instead.
+#[wasm_bindgen]
+pub fn fit_rect(container_w: u32, container_h: u32) -> Box<[u32]> {
+  let (lcd_w, lcd_h) = (LCD_WIDTH as u32, LCD_HEIGHT as u32);
+  let scale = std::cmp::min(container_w / lcd_w, container_h / lcd_h);
+  let (w, h) = if scale > 0 {
+    (lcd_w * scale, lcd_h * scale)
+  } else if container_w * lcd_h <= container_h * lcd_w {
+    (container_w, container_w * lcd_h / lcd_w)
+  } else {
+    (container_h * lcd_w / lcd_h, container_h)
+  };
+  Box::new([(container_w - w) / 2, (container_h - h) / 2, w, h])
+}
+
+fn js_error(context: &str, e: impl std::fmt::Display) -> JsValue {
+  JsValue::from_str(&format!("{}: {}", context, e))
+}
+
+#[derive(Serialize, Deserialize)]
+struct Versioned<T> {
+  version: u32,
+  state: T,
+}
+
+// Errors are plain strings so that these can also run outside of wasm, e.g. in tests.
+fn to_versioned_json(state: impl Serialize) -> Result<String, String> {
+  serde_json::to_string(&Versioned { version: STATE_VERSION, state })
+    .map_err(|e| format!("failed to serialize the state: {}", e))
+}
+
+// The version is checked before the rest is parsed, so that a state from another build is rejected as a whole.
+fn from_versioned_json<T: DeserializeOwned>(json: &str) -> Result<T, String> {
+  #[derive(Deserialize)]
+  struct Header {
+    version: u32,
+  }
+  let header: Header = serde_json::from_str(json).map_err(|e| format!("invalid state: {}", e))?;
+  if header.version != STATE_VERSION {
+    return Err(format!("unsupported state version: {}", header.version));
+  }
+  let versioned: Versioned<T> = serde_json::from_str(json).map_err(|e| format!("invalid state: {}", e))?;
+  Ok(versioned.state)
+}
+
+// Inputs must be sorted by cycle and fall within the emulated cycles.
+fn parse_inputs(inputs_js: JsValue, cycles: u32) -> Result<Vec<Input>, JsValue> {
+  let inputs: Vec<Input> = serde_wasm_bindgen::from_value(inputs_js)
+    .map_err(|e| js_error("invalid inputs", e))?;
+  if inputs.windows(2).any(|w| w[0].cycle > w[1].cycle) {
+    return Err(JsValue::from_str("invalid inputs: not sorted by cycle"));
+  }
+  if let Some(input) = inputs.iter().find(|input| input.cycle > cycles) {
+    return Err(js_error("invalid inputs: cycle out of range", input.cycle));
+  }
+  Ok(inputs)
+}
+
+// `inputs` is reversed, so the next input is the last one.
+fn pop_input_at(inputs: &mut Vec<Input>, cycle: u32) -> Option<Input> {
+  if inputs.last()?.cycle == cycle {
+    inputs.pop()
+  } else {
+    None
+  }
+}
+
 #[wasm_bindgen]
 #[derive(Clone)]
 pub struct GameBoyHandle {
   gameboy: GameBoy,
   gameboy2: Option<GameBoy>,
+  frame: Vec<u8>,
+  frame_count: u32,
+}
+
+// Borrows the machine when saving, so that it isn't copied just to be serialized.
+#[derive(Serialize, Deserialize)]
+struct SaveState<'a> {
+  slot: u8,
+  gameboy: Cow<'a, GameBoy>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -50,40 +142,143 @@ pub struct Input {
 
 #[wasm_bindgen]
 impl GameBoyHandle {
-  pub fn new(cart_rom: &[u8], save: &[u8]) -> Self {
+  pub fn new(cart_rom: &[u8], save: &[u8]) -> Result<GameBoyHandle, JsValue> {
     console_error_panic_hook::set_once();
-    Self {
-      gameboy: GameBoy::new(cart_rom, save),
+    Ok(Self {
+      gameboy: GameBoy::try_new(cart_rom, save).map_err(|e| js_error("cannot load the ROM", e))?,
       gameboy2: None,
-    }
+      frame: vec![0; LCD_PIXELS * 4],
+      frame_count: 0,
+    })
   }
 
   pub fn set_apu_callback(&mut self, callback: Function) {
     self.gameboy.peripherals.apu.set_callback(Rc::new(move |buffer: &[f32]| {
-      callback
-        .call1(&JsValue::null(), &Float32Array::from(buffer))
-        .unwrap();
+      // An exception can't unwind through the emulator, so the failed buffer is just dropped.
+      let _ = callback.call1(&JsValue::null(), &Float32Array::from(buffer));
     }));
   }
 
+  pub fn set_master_gain(&mut self, gain: f32) {
+    self.gameboy.peripherals.apu.set_master_gain(gain);
+  }
+
+  // JSON of `ApuDebug`, the live state of each sound channel.
+  pub fn channel_debug(&self) -> Result<String, JsValue> {
+    serde_json::to_string(&self.gameboy.peripherals.apu.channel_debug())
+      .map_err(|e| js_error("failed to serialize the channel state", e))
+  }
+
   pub fn title(&self) -> String {
     self.gameboy.peripherals.cartridge.title.clone()
   }
 
-  pub fn save(&self) -> Uint8Array {
-    Uint8Array::from(self.gameboy.peripherals.cartridge.sram.as_ref())
+  pub fn cartridge_info(&self) -> Result<String, JsValue> {
+    serde_json::to_string(&self.gameboy.peripherals.cartridge.info())
+      .map_err(|e| js_error("failed to serialize the cartridge info", e))
+  }
+
+  pub fn rom_crc32(&self) -> u32 {
+    self.gameboy.peripherals.cartridge.rom_crc32()
+  }
+
+  pub fn rom_sha1(&self) -> String {
+    self.gameboy.peripherals.cartridge.rom_sha1().iter().map(|b| format!("{:02x}", b)).collect()
+  }
+
+  // Returns [rom_low, rom_high, ram].
+  pub fn current_banks(&self) -> Box<[u32]> {
+    let banks = self.gameboy.peripherals.cartridge.current_banks();
+    Box::new([banks.rom_low as u32, banks.rom_high as u32, banks.ram as u32])
+  }
+
+  pub fn has_battery(&self) -> bool {
+    self.gameboy.peripherals.cartridge.has_battery()
+  }
+
+  // See `GameBoy::export_save` for the format.
+  pub fn export_save(&self) -> Uint8Array {
+    Uint8Array::from(self.gameboy.export_save().as_slice())
+  }
+
+  pub fn import_save(&mut self, save: &[u8]) -> Result<(), JsValue> {
+    self.gameboy.import_save(save).map_err(|e| JsValue::from_str(&e))
+  }
+
+  pub fn save_dirty(&mut self) -> bool {
+    self.gameboy.peripherals.cartridge.take_dirty()
+  }
+
+  pub fn reset(&mut self) {
+    self.gameboy.reset();
+  }
+
+  pub fn hard_reset(&mut self) {
+    self.gameboy.hard_reset();
+  }
+
+  pub fn to_json(&self) -> Result<String, JsValue> {
+    to_versioned_json(&self.gameboy).map_err(|e| JsValue::from_str(&e))
   }
 
-  pub fn to_json(&self) -> String {
-    serde_json::to_string(&self.gameboy).unwrap()
+  // `slot` is stored with the state so that a state saved in one slot can't be loaded into another by mistake.
+  pub fn save_state_slot(&self, slot: u8) -> Result<String, JsValue> {
+    to_versioned_json(SaveState { slot, gameboy: Cow::Borrowed(&self.gameboy) }).map_err(|e| JsValue::from_str(&e))
+  }
+
+  pub fn load_state_slot(&mut self, slot: u8, json: &str) -> Result<(), JsValue> {
+    let state: SaveState = from_versioned_json(json).map_err(|e| JsValue::from_str(&e))?;
+    if state.slot != slot {
+      return Err(js_error("the state belongs to another slot", state.slot));
+    }
+    self.gameboy.restore(state.gameboy.into_owned());
+    Ok(())
+  }
+
+  pub fn set_instruction_snapshots(&mut self, on: bool) {
+    self.gameboy.set_instruction_snapshots(on);
+  }
+
+  pub fn step_back(&mut self) -> bool {
+    self.gameboy.step_back()
+  }
+
+  pub fn compatibility_warning(&self) -> Option<String> {
+    self.gameboy.compatibility_warning()
+  }
+
+  pub fn is_halted(&self) -> bool {
+    self.gameboy.cpu.is_halted()
+  }
+
+  pub fn set_mono(&mut self, on: bool) {
+    self.gameboy.peripherals.apu.set_mono(on);
+  }
+
+  pub fn start_recording(&mut self) {
+    self.gameboy.start_recording();
+  }
+
+  pub fn movie(&self) -> Result<Option<String>, JsValue> {
+    self.gameboy.movie()
+      .map(|m| serde_json::to_string(&m).map_err(|e| js_error("failed to serialize the movie", e)))
+      .transpose()
+  }
+
+  pub fn play_movie(&mut self, json: String) -> Result<(), JsValue> {
+    let movie = serde_json::from_str(&json).map_err(|e| js_error("invalid movie", e))?;
+    self.gameboy.play_movie(movie);
+    Ok(())
   }
 
   pub fn _clone(&self) -> Self {
     self.clone()
   }
 
-  pub fn connect(&mut self, json: String) {
-    self.gameboy2 = serde_json::from_str(&json).ok();
+  pub fn connect(&mut self, json: String) -> Result<(), JsValue> {
+    self.gameboy2 = None;
+    self.gameboy2 = Some(from_versioned_json(&json).map_err(|e| JsValue::from_str(&e))?);
+    Ok(())
   }
 
   pub fn disconnect(&mut self) {
@@ -111,47 +306,70 @@ impl GameBoyHandle {
     ret
   }
 
-  pub fn emulate(&mut self, cycles: u32, inputs1_js: JsValue, inputs2_js: JsValue) {
-    let mut inputs1: Vec<Input> = serde_wasm_bindgen::from_value::<Vec<Input>>(inputs1_js).unwrap();
-    let mut inputs2: Vec<Input> = serde_wasm_bindgen::from_value::<Vec<Input>>(inputs2_js).unwrap();
+  pub fn emulate(&mut self, cycles: u32, inputs1_js: JsValue, inputs2_js: JsValue) -> Result<(), JsValue> {
+    let apu_callback = self.gameboy.peripherals.apu.callback.take();
+    let ret = self.run(cycles, inputs1_js, inputs2_js, None);
+    self.gameboy.peripherals.apu.callback = apu_callback;
+    ret.map(|_| ())
+  }
+
+  // Like `emulate`, but keeps the audio running and copies only every `skip + 1`th frame
+  // to `latest_frame_buffer`. Returns whether a fresh frame was copied.
+  pub fn emulate_with_skip(&mut self, cycles: u32, skip: u32, inputs1_js: JsValue, inputs2_js: JsValue) -> Result<bool, JsValue> {
+    self.run(cycles, inputs1_js, inputs2_js, Some(skip))
+  }
+
+  fn run(&mut self, cycles: u32, inputs1_js: JsValue, inputs2_js: JsValue, skip: Option<u32>) -> Result<bool, JsValue> {
+    let mut inputs1 = parse_inputs(inputs1_js, cycles)?;
+    let mut inputs2 = parse_inputs(inputs2_js, cycles)?;
     inputs1.reverse();
     inputs2.reverse();
-    let apu_callback = self.gameboy.peripherals.apu.callback.take();
+    let mut fresh = false;
     for i in 0..=cycles {
-      if let Some(input) = inputs1.last() {
-        if i == input.cycle {
-          if input.down {
-            self.key_down(&input.code);
-          } else {
-            self.key_up(&input.code);
-          }
-          inputs1.pop();
-        }        
+      while let Some(input) = pop_input_at(&mut inputs1, i) {
+        if input.down {
+          self.key_down(&input.code);
+        } else {
+          self.key_up(&input.code);
+        }
+      }
+      while let Some(input) = pop_input_at(&mut inputs2, i) {
+        if input.down {
+          self.key_down2(&input.code);
+        } else {
+          self.key_up2(&input.code);
+        }
       }
-      if let Some(input) = inputs2.last() {
-        if i == input.cycle {
-          if input.down {
-            self.key_down2(&input.code);
-          } else {
-            self.key_up2(&input.code);
-          }
-          inputs2.pop();
+      if let (true, Some(skip)) = (self.emulate_cycle(), skip) {
+        if self.frame_count == 0 {
+          self.frame.copy_from_slice(&self.gameboy.peripherals.ppu.buffer);
+          fresh = true;
         }
+        self.frame_count = if self.frame_count >= skip { 0 } else { self.frame_count + 1 };
       }
-      self.emulate_cycle();
     }
-    assert!(inputs1.is_empty());
-    assert!(inputs2.is_empty());
-    self.gameboy.peripherals.apu.set_callback(apu_callback.unwrap());
+    Ok(fresh)
+  }
+
+  pub fn latest_frame_buffer(&self) -> Uint8ClampedArray {
+    Uint8ClampedArray::from(self.frame.as_ref())
   }
 
   pub fn frame_buffer(&self) -> Uint8ClampedArray {
-    Uint8ClampedArray::from(self.gameboy.peripherals.ppu.buffer.as_ref())
+    Uint8ClampedArray::from(self.gameboy.peripherals.ppu.front_buffer())
+  }
+
+  pub fn set_layer_enabled(&mut self, layer: &str, on: bool) -> bool {
+    if let Some(l) = str2layer(layer) {
+      self.gameboy.peripherals.ppu.set_layer_enabled(l, on);
+      return true;
+    }
+    false
   }
 
   pub fn key_down(&mut self, k: &str) -> bool {
     if let Some(j) = key2joy(k) {
-      self.gameboy.peripherals.joypad.button_down(&mut self.gameboy.cpu.interrupts, j);
+      self.gameboy.button_down(j);
       return true;
     }
     false
@@ -159,7 +377,7 @@ impl GameBoyHandle {
 
   pub fn key_up(&mut self, k: &str) -> bool {
     if let Some(j) = key2joy(k) {
-      self.gameboy.peripherals.joypad.button_up(j);
+      self.gameboy.button_up(j);
       return true;
     }
     false
@@ -180,16 +398,30 @@ impl GameBoyHandle {
   }
 }
 
+// The number of buffers `is_full` allows to be queued by default.
+const DEFAULT_MAX_QUEUED: usize = 15;
+
 #[wasm_bindgen]
-pub struct AudioHandle(OutputStream, OutputStreamHandle, Sink);
+pub struct AudioHandle(OutputStream, OutputStreamHandle, Sink, usize);
 
 #[wasm_bindgen]
 impl AudioHandle {
-  pub fn new() -> Self {
-    let (stream, handle) = OutputStream::try_default().unwrap();
-    let sink = Sink::try_new(&handle).unwrap();
+  pub fn new() -> Result<AudioHandle, JsValue> {
+    let (stream, handle) = OutputStream::try_default().map_err(|e| js_error("no audio output", e))?;
+    let sink = Sink::try_new(&handle).map_err(|e| js_error("cannot play audio", e))?;
     sink.play();
-    Self(stream, handle, sink)
+    Ok(Self(stream, handle, sink, DEFAULT_MAX_QUEUED))
+  }
+  // Lower values reduce the latency, but the audio may underrun.
+  pub fn set_max_queued(&mut self, buffers: usize) {
+    if buffers < 2 {
+      warn("A queue of less than 2 audio buffers is likely to underrun.");
+    }
+    self.3 = buffers;
+  }
+  // Whether the emulation should wait for the queued audio to play.
+  pub fn is_full(&self) -> bool {
+    self.2.len() >= self.3
   }
   pub fn append(&self, buffer: &[f32]) {
     self.2.append(SamplesBuffer::new(2, SAMPLE_RATE as u32, buffer));
@@ -197,4 +429,64 @@ impl AudioHandle {
   pub fn length(&self) -> usize {
     self.2.len()
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A blank ROM ONLY cartridge with a valid header.
+  fn rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x14D] = rom[0x134..0x14D].iter().fold(0u8, |acc, &b| acc.wrapping_sub(b).wrapping_sub(1));
+    rom
+  }
+
+  #[test]
+  fn save_state_round_trip() {
+    let mut gameboy = GameBoy::new(&rom(), &[]);
+    gameboy.reset();
+    for _ in 0..1000 {
+      gameboy.emulate_cycle();
+    }
+    let json = to_versioned_json(SaveState { slot: 3, gameboy: Cow::Borrowed(&gameboy) }).unwrap();
+    let state: SaveState = from_versioned_json(&json).unwrap();
+    assert_eq!(state.slot, 3);
+    let mut loaded = GameBoy::new(&rom(), &[]);
+    loaded.restore(state.gameboy.into_owned());
+    assert_eq!(loaded.cpu.registers().pc, gameboy.cpu.registers().pc);
+    for _ in 0..1000 {
+      gameboy.emulate_cycle();
+      loaded.emulate_cycle();
+    }
+    assert_eq!(loaded.cpu.registers().pc, gameboy.cpu.registers().pc);
+    assert_eq!(loaded.frame_hash(), gameboy.frame_hash());
+  }
+  #[test]
+  fn rom_checksums_and_banks() {
+    let handle = GameBoyHandle::new(&rom(), &[]).unwrap();
+    assert_eq!(handle.rom_crc32(), 0x6D5DD162);
+    assert_eq!(handle.rom_sha1(), "8d177a426b41afbc2760422af302feb77bd90d95");
+    assert_eq!(*handle.current_banks(), [0, 1, 0]);
+  }
+  #[test]
+  fn other_state_versions_are_rejected() {
+    let gameboy = GameBoy::new(&rom(), &[]);
+    let json = to_versioned_json(&gameboy).unwrap();
+    assert!(from_versioned_json::<GameBoy>(&json).is_ok());
+    let other = json.replacen(&format!("\"version\":{}", STATE_VERSION), &format!("\"version\":{}", STATE_VERSION + 1), 1);
+    assert_ne!(other, json);
+    assert_eq!(from_versioned_json::<GameBoy>(&other).err().unwrap(), format!("unsupported state version: {}", STATE_VERSION + 1));
+  }
+  #[test]
+  fn fit_rect_centers_an_integer_scale() {
+    // Exact aspect: fills the container.
+    assert_eq!(*fit_rect(320, 288), [0, 0, 320, 288]);
+    // Wide: pillarboxed at the height's scale.
+    assert_eq!(*fit_rect(800, 288), [240, 0, 320, 288]);
+    // Tall: letterboxed at the width's scale.
+    assert_eq!(*fit_rect(160, 600), [0, 228, 160, 144]);
+    // Smaller than the LCD: shrinks, keeping the aspect.
+    assert_eq!(*fit_rect(80, 200), [0, 64, 80, 72]);
+  }
+}
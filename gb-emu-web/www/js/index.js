@@ -142,7 +142,7 @@ class GameBoyRunner {
 
     document.getElementById("save").onclick = (_) => {
       if (!this.gameboy.is_on()) return;
-      const sav_data = this.gameboy.gameboy.save();
+      const sav_data = this.gameboy.gameboy.export_save();
       if (sav_data.length === 0) return;
       var a = document.createElement("a");
       a.style = "display: none";
@@ -0,0 +1,36 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use gbemu::gameboy::GameBoy;
+
+const CYCLES: u64 = 70224 / 4; // one frame
+
+// A 32 KiB ROM which turns on the LCD and spins in a loop of loads and arithmetic.
+fn rom() -> Vec<u8> {
+  let mut rom = vec![0; 0x8000];
+  rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]); // nop; jp 0x0150
+  rom[0x134..0x13B].copy_from_slice(b"BENCH\0\0");
+  rom[0x14D] = rom[0x134..0x14D].iter().fold(0u8, |acc, &b| acc.wrapping_sub(b).wrapping_sub(1));
+  rom[0x150..0x15E].copy_from_slice(&[
+    0x3E, 0x91,       // ld a, 0x91
+    0xE0, 0x40,       // ldh (0x40), a
+    0x21, 0x00, 0xC0, // ld hl, 0xC000
+    0x22,             // ld (hl+), a
+    0x3C,             // inc a
+    0xCB, 0x37,       // swap a
+    0x18, 0xF9,       // jr -7
+    0x00,
+  ]);
+  rom
+}
+
+fn emulate(c: &mut Criterion) {
+  let mut gameboy = GameBoy::new(&rom(), &[]);
+  gameboy.reset();
+  let mut group = c.benchmark_group("emulate");
+  group.throughput(Throughput::Elements(CYCLES));
+  group.bench_function("run_cycles", |b| b.iter(|| gameboy.run_cycles(CYCLES)));
+  group.finish();
+}
+
+criterion_group!(benches, emulate);
+criterion_main!(benches);
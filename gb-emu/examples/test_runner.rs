@@ -0,0 +1,104 @@
+// Runs every .gb/.gbc test ROM in a directory headless and prints a summary.
+// A ROM passes when it hits the Mooneye software breakpoint with the success registers,
+// or prints "Passed" to the link port like Blargg's tests.
+//
+//   cargo run --release --example test_runner -- <dir> [frames]
+use std::{env, fs, panic, path::Path, process::exit};
+
+use gbemu::gameboy::GameBoy;
+
+const DEFAULT_FRAMES: usize = 60 * 60;
+
+enum Outcome {
+  Passed,
+  Failed,
+  Timeout,
+  Error(String),
+}
+
+fn run(path: &Path, frames: usize) -> (Outcome, String) {
+  let rom = match fs::read(path) {
+    Ok(rom) => rom,
+    Err(e) => return (Outcome::Error(e.to_string()), String::new()),
+  };
+  let mut gameboy = match GameBoy::try_new(&rom, &[]) {
+    Ok(gameboy) => gameboy,
+    Err(e) => return (Outcome::Error(e), String::new()),
+  };
+  gameboy.reset();
+  let mut serial = String::new();
+  for _ in 0..frames {
+    loop {
+      if gameboy.emulate_cycle() {
+        break;
+      }
+    }
+    serial.extend(gameboy.take_serial_output().into_iter().map(|b| b as char));
+    // The link port text goes first, so that a stray LD B,B in a Blargg ROM doesn't decide the result.
+    if serial.contains("Passed") {
+      return (Outcome::Passed, serial);
+    }
+    if serial.contains("Failed") {
+      return (Outcome::Failed, serial);
+    }
+    match gameboy.check_mooneye_result() {
+      Some(true) => return (Outcome::Passed, serial),
+      Some(false) => return (Outcome::Failed, serial),
+      None => {},
+    }
+  }
+  (Outcome::Timeout, serial)
+}
+
+fn main() {
+  let args: Vec<String> = env::args().collect();
+  if args.len() < 2 {
+    eprintln!("Usage: {} <dir> [frames]", args[0]);
+    exit(2);
+  }
+  let frames = match args.get(2).map(|v| v.parse::<usize>()) {
+    None => DEFAULT_FRAMES,
+    Some(Ok(frames)) => frames,
+    Some(Err(_)) => {
+      eprintln!("frames must be a number.");
+      exit(2);
+    }
+  };
+  let mut paths: Vec<_> = match fs::read_dir(&args[1]) {
+    Ok(dir) => dir.filter_map(|e| e.ok().map(|e| e.path()))
+      .filter(|p| matches!(p.extension().and_then(|e| e.to_str()), Some("gb" | "gbc")))
+      .collect(),
+    Err(e) => {
+      eprintln!("Failed to read \"{}\": {}", args[1], e);
+      exit(2);
+    }
+  };
+  paths.sort();
+
+  let mut passed = 0;
+  for path in &paths {
+    let name = path.file_name().unwrap().to_string_lossy();
+    // Unemulated instructions such as STOP panic, which shouldn't stop the other ROMs from running.
+    let (outcome, serial) = panic::catch_unwind(|| run(path, frames))
+      .unwrap_or_else(|_| (Outcome::Error(String::from("panicked")), String::new()));
+    let result = match outcome {
+      Outcome::Passed => {
+        passed += 1;
+        String::from("PASS")
+      },
+      Outcome::Failed => String::from("FAIL"),
+      Outcome::Timeout => String::from("TIMEOUT"),
+      Outcome::Error(e) => format!("ERROR ({})", e),
+    };
+    println!("{:<48} {}", name, result);
+    if !serial.is_empty() && result != "PASS" {
+      for line in serial.lines().filter(|l| !l.trim().is_empty()) {
+        println!("    {}", line.trim());
+      }
+    }
+  }
+  println!("{}/{} passed", passed, paths.len());
+  if passed < paths.len() {
+    exit(1);
+  }
+}
@@ -1,7 +1,7 @@
 // https://nightshade256.github.io/2021/03/27/gb-sound-emulation.html
 use serde::{Deserialize, Serialize};
 
-use std::{cmp::{max, min}, rc::Rc};
+use std::{cmp::{max, min}, collections::VecDeque, rc::Rc};
 
 use crate::{
   CPU_CLOCK_HZ,
@@ -9,6 +9,9 @@ use crate::{
   SAMPLE_RATE,
 };
 
+// Interleaved stereo samples kept for `drain_samples`. The oldest ones are dropped when they aren't drained in time.
+const RING_CAPACITY: usize = SAMPLES * 2 * 16;
+
 const WAVE_DUTY: [[f32; 8]; 4] = [
   [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0], // 12.5%
   [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0], // 25%
@@ -16,15 +19,46 @@ const WAVE_DUTY: [[f32; 8]; 4] = [
   [0.0, 0.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0], // 75%
 ];
 
+fn default_sample_divisor() -> u128 {
+  CPU_CLOCK_HZ / SAMPLE_RATE
+}
+
+// The fraction of the high-pass capacitor charge kept per T-cycle, as measured on DMG.
+const CHARGE_FACTOR: f32 = 0.999958;
+
+fn default_master_gain() -> f32 {
+  1.0
+}
+
+// Live state of one channel, for visualizers.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct ChannelDebug {
+  pub enabled: bool,
+  pub dac_enabled: bool,
+  // The envelope volume 0-15. For channel 3, the NR32 output level code instead.
+  pub volume: u8,
+  // The 11-bit frequency value. For channel 4, the LFSR clock period in T-cycles instead.
+  pub frequency: u32,
+  // The wave duty 0-3 of channels 1 and 2.
+  pub duty: u8,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct ApuDebug {
+  pub channels: [ChannelDebug; 4],
+}
+
 trait Channel {
   fn read_nrxx(&self, addr: u16) -> u8;
   fn write_nrxx(&mut self, addr: u16, val: u8);
   fn emulate_t_cycle(&mut self);
   fn dac_output(&self) -> f32;
+  fn dac_active(&self) -> bool;
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Apu {
+  is_cgb: bool,
   enabled: bool,
   nr50: u8,
   nr51: u8,
@@ -36,13 +70,27 @@ pub struct Apu {
   channel4: Channel4,
   samples: Vec<f32>,
   sample_idx: usize,
+  #[serde(default = "default_sample_divisor")]
+  sample_divisor: u128,
+  // None in states saved before the filter existed, until the next sample charges them to the current levels.
+  #[serde(default)]
+  capacitors: Option<[f32; 4]>,
+  // A user setting rather than machine state, so it is not saved.
+  #[serde(skip, default = "default_master_gain")]
+  master_gain: f32,
+  #[serde(skip)]
+  mono: bool,
+  // Only allocated and filled once `drain_samples` is first called, so that callback users don't pay for it.
+  #[serde(skip)]
+  pub(crate) ring: Option<VecDeque<f32>>,
   #[serde(skip)]
   pub callback: Option<Rc<dyn Fn(&[f32])>>,
 }
 
 impl Apu {
-  pub fn new() -> Self {
+  pub fn new(is_cgb: bool) -> Self {
     Self {
+      is_cgb,
       enabled: false,
       nr50: 0,
       nr51: 0,
@@ -54,14 +102,107 @@ impl Apu {
       channel4: Channel4::default(),
       samples: vec![0.0; SAMPLES * 2],
       sample_idx: 0,
+      sample_divisor: default_sample_divisor(),
+      capacitors: Some([0.0; 4]),
+      master_gain: default_master_gain(),
+      mono: false,
+      ring: None,
       callback: None,
     }
   }
 
+  // Keeps the output at SAMPLE_RATE in real time when the machine runs at a non-standard clock.
+  pub fn set_clock_hz(&mut self, clock_hz: u128) {
+    self.sample_divisor = max(clock_hz / SAMPLE_RATE, 1);
+  }
+
+  // Scales the final output on top of NR50. `gain` is clamped to 0.0-1.0.
+  pub fn set_master_gain(&mut self, gain: f32) {
+    self.master_gain = gain.clamp(0.0, 1.0);
+  }
+
+  pub fn master_gain(&self) -> f32 {
+    self.master_gain
+  }
+
+  // Outputs the average of both sides on each side, for a single speaker.
+  pub fn set_mono(&mut self, on: bool) {
+    self.mono = on;
+  }
+
+  pub fn channel_debug(&self) -> ApuDebug {
+    let (ch1, ch2, ch3, ch4) = (&self.channel1, &self.channel2, &self.channel3, &self.channel4);
+    ApuDebug {
+      channels: [
+        ChannelDebug {
+          enabled: ch1.enabled,
+          dac_enabled: ch1.dac_enabled,
+          volume: ch1.current_volume,
+          frequency: ch1.frequency as u32,
+          duty: ch1.wave_duty_pattern,
+        },
+        ChannelDebug {
+          enabled: ch2.enabled,
+          dac_enabled: ch2.dac_enabled,
+          volume: ch2.current_volume,
+          frequency: ch2.frequency as u32,
+          duty: ch2.wave_duty_pattern,
+        },
+        ChannelDebug {
+          enabled: ch3.enabled,
+          dac_enabled: ch3.dac_enabled,
+          volume: ch3.output_level,
+          frequency: ch3.frequency as u32,
+          duty: 0,
+        },
+        ChannelDebug {
+          enabled: ch4.enabled,
+          dac_enabled: ch4.dac_enabled,
+          volume: ch4.current_volume,
+          frequency: (max(8, ch4.divisor_code << 4) as u32) << ch4.shift_amount,
+          duty: 0,
+        },
+      ],
+    }
+  }
+
   pub fn set_callback(&mut self, callback: Rc<dyn Fn(&[f32])>) {
     self.callback = Some(callback);
   }
 
+  // Moves the oldest interleaved stereo samples into `out` and returns how many were written.
+  // Samples are kept from the first call on, so that call returns 0.
+  pub fn drain_samples(&mut self, out: &mut [f32]) -> usize {
+    let ring = self.ring.get_or_insert_with(|| VecDeque::with_capacity(RING_CAPACITY));
+    let n = min(out.len(), ring.len());
+    for (o, s) in out.iter_mut().zip(ring.drain(..n)) {
+      *o = s;
+    }
+    n
+  }
+
+  // Each channel goes through the high-pass filter which removes the DC offset of its DAC.
+  // A disabled DAC outputs the center level, so it becomes silent without a step.
+  fn high_pass(&mut self) -> [f32; 4] {
+    let charge = CHARGE_FACTOR.powi(self.sample_divisor as i32);
+    let inputs = [
+      (self.channel1.dac_active(), self.channel1.dac_output()),
+      (self.channel2.dac_active(), self.channel2.dac_output()),
+      (self.channel3.dac_active(), self.channel3.dac_output()),
+      (self.channel4.dac_active(), self.channel4.dac_output()),
+    ];
+    // Starting from 0 instead would be a step of the full DC offset.
+    let capacitors = self.capacitors.get_or_insert(inputs.map(|(_, input)| input));
+    let mut ret = [0.0; 4];
+    for (i, (active, input)) in inputs.into_iter().enumerate() {
+      if active {
+        ret[i] = input - capacitors[i];
+        capacitors[i] = input - ret[i] * charge;
+      }
+    }
+    ret
+  }
+
   pub fn emulate_cycle(&mut self) {
     for _ in 0..4 {
       self.cycles += 1;
@@ -80,21 +221,35 @@ impl Apu {
         self.fs = (self.fs + 1) & 7;
       }
 
-      if self.cycles % (CPU_CLOCK_HZ / SAMPLE_RATE) == 0 {
+      if self.cycles % self.sample_divisor == 0 {
+        let [out1, out2, out3, out4] = self.high_pass();
         let left_sample = (
-            (((self.nr51 >> 7) & 0b1) as f32) * self.channel4.dac_output()
-          + (((self.nr51 >> 6) & 0b1) as f32) * self.channel3.dac_output()
-          + (((self.nr51 >> 5) & 0b1) as f32) * self.channel2.dac_output()
-          + (((self.nr51 >> 4) & 0b1) as f32) * self.channel1.dac_output()
+            (((self.nr51 >> 7) & 0b1) as f32) * out4
+          + (((self.nr51 >> 6) & 0b1) as f32) * out3
+          + (((self.nr51 >> 5) & 0b1) as f32) * out2
+          + (((self.nr51 >> 4) & 0b1) as f32) * out1
         ) / 4.0;
         let right_sample = (
-          (((self.nr51 >> 3) & 0b1) as f32) * self.channel4.dac_output()
-        + (((self.nr51 >> 2) & 0b1) as f32) * self.channel3.dac_output()
-        + (((self.nr51 >> 1) & 0b1) as f32) * self.channel2.dac_output()
-        + (( self.nr51       & 0b1) as f32) * self.channel1.dac_output()
+          (((self.nr51 >> 3) & 0b1) as f32) * out4
+        + (((self.nr51 >> 2) & 0b1) as f32) * out3
+        + (((self.nr51 >> 1) & 0b1) as f32) * out2
+        + (( self.nr51       & 0b1) as f32) * out1
       ) / 4.0;
-        self.samples[self.sample_idx * 2] = (((self.nr50 >> 4) & 0x7) as f32 / 7.0) * left_sample;
-        self.samples[self.sample_idx * 2 + 1] = ((self.nr50 & 0x7) as f32 / 7.0) * right_sample;
+        let mut left = (((self.nr50 >> 4) & 0x7) as f32 / 7.0) * left_sample * self.master_gain;
+        let mut right = ((self.nr50 & 0x7) as f32 / 7.0) * right_sample * self.master_gain;
+        if self.mono {
+          left = (left + right) / 2.0;
+          right = left;
+        }
+        self.samples[self.sample_idx * 2] = left;
+        self.samples[self.sample_idx * 2 + 1] = right;
+        if let Some(ring) = self.ring.as_mut() {
+          if ring.len() + 2 > RING_CAPACITY {
+            ring.drain(..2);
+          }
+          ring.push_back(left);
+          ring.push_back(right);
+        }
         self.sample_idx += 1;
       }
 
@@ -130,7 +285,9 @@ impl Apu {
   }
 
   pub fn write(&mut self, addr: u16, mut val: u8) {
-    if !self.enabled && ![0xFF11, 0xFF16, 0xFF1B, 0xFF20, 0xFF26].contains(&addr) && !(0xFF30..=0xFF3F).contains(&addr) {
+    // Only DMG allows the length registers to be written while powered off.
+    let writable_off = !self.is_cgb && [0xFF11, 0xFF16, 0xFF1B, 0xFF20].contains(&addr);
+    if !self.enabled && !writable_off && addr != 0xFF26 && !(0xFF30..=0xFF3F).contains(&addr) {
       return;
     }
 
@@ -144,9 +301,22 @@ impl Apu {
       0xFF26          => {
         let enabled = val & 0x80 > 0;
         if !enabled && self.enabled {
+          let lengths = (
+            self.channel1.length_timer,
+            self.channel2.length_timer,
+            self.channel3.length_timer,
+            self.channel4.length_timer,
+          );
           for addr in 0xFF10..=0xFF25 {
             self.write(addr, 0x00);
           }
+          // DMG keeps the length counters across a power cycle, CGB resets them.
+          if !self.is_cgb {
+            self.channel1.length_timer = lengths.0;
+            self.channel2.length_timer = lengths.1;
+            self.channel3.length_timer = lengths.2;
+            self.channel4.length_timer = lengths.3;
+          }
         } else if enabled && !self.enabled {
           self.fs = 0;
           self.channel1.wave_duty_position = 0;
@@ -323,8 +493,11 @@ impl Channel for Channel1 {
     }
     self.frequency_timer -= 1;
   }
+  fn dac_active(&self) -> bool {
+    self.dac_enabled && self.enabled
+  }
   fn dac_output(&self) -> f32 {
-    if self.dac_enabled && self.enabled {
+    if self.dac_active() {
       let dac_input = 
         WAVE_DUTY[self.wave_duty_pattern as usize][self.wave_duty_position]
         * self.current_volume as f32;
@@ -445,8 +618,11 @@ impl Channel for Channel2 {
     }
     self.frequency_timer -= 1;
   }
+  fn dac_active(&self) -> bool {
+    self.dac_enabled && self.enabled
+  }
   fn dac_output(&self) -> f32 {
-    if self.dac_enabled && self.enabled {
+    if self.dac_active() {
       let dac_input = 
         WAVE_DUTY[self.wave_duty_pattern as usize][self.wave_duty_position]
         * self.current_volume as f32;
@@ -561,8 +737,11 @@ impl Channel for Channel3 {
     }
     self.frequency_timer -= 1;
   }
+  fn dac_active(&self) -> bool {
+    self.dac_enabled && self.enabled
+  }
   fn dac_output(&self) -> f32 {
-    if self.dac_enabled && self.enabled {
+    if self.dac_active() {
       let dac_input = ((0x0F & (
         self.wave_ram[self.wave_duty_position >> 1]
         >> ((self.wave_duty_position & 1) << 2) // upper/lower 4 bits
@@ -701,8 +880,11 @@ impl Channel for Channel4 {
     }
     self.frequency_timer -= 1;
   }
+  fn dac_active(&self) -> bool {
+    self.dac_enabled && self.enabled
+  }
   fn dac_output(&self) -> f32 {
-    if self.dac_enabled && self.enabled {
+    if self.dac_active() {
       let dac_input = (self.lfsr & 0b01) as f32 * self.current_volume as f32;
 
       (dac_input / 7.5) - 1.0
@@ -711,3 +893,134 @@ impl Channel for Channel4 {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn powered_on(is_cgb: bool) -> Apu {
+    let mut apu = Apu::new(is_cgb);
+    apu.write(0xFF26, 0x80);
+    apu
+  }
+
+  #[test]
+  fn dmg_power_off_keeps_lengths() {
+    let mut apu = powered_on(false);
+    apu.write(0xFF11, 0x80 | 10);
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF30, 0x12);
+    apu.write(0xFF26, 0x00);
+    assert_eq!(apu.channel1.length_timer, 64 - 10);
+    assert_eq!(apu.read(0xFF12), 0x00);
+    assert_eq!(apu.read(0xFF30), 0x12);
+    // Only the length bits can be written while off, the duty stays cleared.
+    apu.write(0xFF11, 0xC0 | 20);
+    apu.write(0xFF12, 0xF0);
+    assert_eq!(apu.channel1.length_timer, 64 - 20);
+    assert_eq!(apu.read(0xFF11), 0x3F);
+    assert_eq!(apu.read(0xFF12), 0x00);
+  }
+
+  #[test]
+  fn cgb_power_off_resets_lengths() {
+    let mut apu = powered_on(true);
+    apu.write(0xFF11, 0x80 | 10);
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF30, 0x12);
+    apu.write(0xFF26, 0x00);
+    assert_eq!(apu.channel1.length_timer, 64);
+    assert_eq!(apu.read(0xFF12), 0x00);
+    assert_eq!(apu.read(0xFF30), 0x12);
+    apu.write(0xFF11, 20);
+    assert_eq!(apu.channel1.length_timer, 64);
+  }
+  // Channel 1 plays a square wave at full volume on both sides.
+  fn square_wave() -> Apu {
+    let mut apu = powered_on(false);
+    for (addr, val) in [(0xFF24, 0x77), (0xFF25, 0xFF), (0xFF11, 0x80), (0xFF12, 0xF0), (0xFF13, 0x00), (0xFF14, 0x87)] {
+      apu.write(addr, val);
+    }
+    apu
+  }
+
+  // Runs until `n` interleaved stereo samples are output and returns them.
+  fn samples(apu: &mut Apu, n: usize) -> Vec<f32> {
+    let mut out = vec![0.0; n];
+    apu.drain_samples(&mut []);
+    while apu.ring.as_ref().unwrap().len() < n {
+      apu.emulate_cycle();
+    }
+    apu.drain_samples(&mut out);
+    out
+  }
+
+  #[test]
+  fn drained_samples_are_in_fifo_order() {
+    let mut apu = square_wave();
+    let sent = Rc::new(std::cell::RefCell::new(Vec::new()));
+    let sent_clone = sent.clone();
+    apu.set_callback(Rc::new(move |buffer: &[f32]| sent_clone.borrow_mut().extend_from_slice(buffer)));
+    assert_eq!(apu.drain_samples(&mut []), 0);
+    while sent.borrow().is_empty() {
+      apu.emulate_cycle();
+    }
+    let mut out = vec![0.0; SAMPLES * 2 + 1];
+    assert_eq!(apu.drain_samples(&mut out), SAMPLES * 2);
+    assert_eq!(out[..SAMPLES * 2], sent.borrow()[..]);
+    assert!(out.iter().any(|&s| s != out[0]));
+  }
+
+  #[test]
+  fn no_ring_without_a_consumer() {
+    let mut apu = powered_on(false);
+    for _ in 0..0x1000 {
+      apu.emulate_cycle();
+    }
+    assert!(apu.ring.is_none());
+  }
+  #[test]
+  fn master_gain_scales_the_output() {
+    let full = samples(&mut square_wave(), 0x1000);
+    assert!(full.iter().any(|&s| s != 0.0));
+    let mut half = square_wave();
+    half.set_master_gain(0.5);
+    assert_eq!(samples(&mut half, 0x1000), full.iter().map(|s| s * 0.5).collect::<Vec<_>>());
+    let mut silent = square_wave();
+    silent.set_master_gain(0.0);
+    assert!(samples(&mut silent, 0x1000).iter().all(|&s| s == 0.0));
+  }
+  #[test]
+  fn restoring_without_capacitors_has_no_step() {
+    // Channel 1 outputs a constant DC offset at volume 0.
+    let mut apu = powered_on(false);
+    for (addr, val) in [(0xFF24, 0x77), (0xFF25, 0xFF), (0xFF12, 0x08), (0xFF14, 0x80)] {
+      apu.write(addr, val);
+    }
+    let first = samples(&mut apu, 2)[0];
+    assert!(first < -0.2);
+    // Until the filter settles.
+    for _ in 0..4 {
+      samples(&mut apu, 0x2000);
+    }
+    let last = *samples(&mut apu, 2).last().unwrap();
+    assert!(last.abs() < 0.001);
+
+    let mut json: serde_json::Value = serde_json::to_value(&apu).unwrap();
+    json.as_object_mut().unwrap().remove("capacitors").unwrap();
+    let mut restored: Apu = serde_json::from_value(json).unwrap();
+    assert!(samples(&mut restored, 0x100).iter().all(|s| (s - last).abs() < 0.001));
+  }
+  #[test]
+  fn channel_debug_reports_the_registers() {
+    let mut apu = square_wave();
+    apu.write(0xFF1C, 0x20);
+    apu.write(0xFF22, 0x21);
+    let [ch1, ch2, ch3, ch4] = apu.channel_debug().channels;
+    assert!(ch1.enabled && ch1.dac_enabled);
+    assert_eq!((ch1.volume, ch1.frequency, ch1.duty), (15, 0x700, 2));
+    assert!(!ch2.enabled);
+    assert_eq!(ch3.volume, 1);
+    assert_eq!(ch4.frequency, 16 << 2);
+  }
+}
@@ -4,6 +4,9 @@ use serde::{Deserialize, Serialize};
 pub struct Bootrom {
   rom: Vec<u8>,
   active: bool,
+  // KEY0, which the CGB boot ROM uses to select CGB or DMG compatibility mode.
+  #[serde(default)]
+  key0: u8,
 }
 
 impl Bootrom {
@@ -156,21 +159,64 @@ impl Bootrom {
         0x32, 0x0d, 0x20, 0xf9, 0x2e, 0x0f, 0x18, 0xf5, 0xf1, 0xc9, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
       ],
       active: true,
+      key0: 0,
     }
   }
   pub fn from_data(rom: Vec<u8>) -> Self {
     Self {
       rom,
       active: true,
+      key0: 0,
     }
   }
+  // The same boot ROM, mapped in again as at power on.
+  pub fn rebooted(&self) -> Self {
+    Self::from_data(self.rom.clone())
+  }
   pub fn is_active(&self) -> bool {
     self.active
   }
+  // Whether the boot ROM wrote KEY0 to select DMG compatibility mode.
+  pub fn dmg_compat(&self) -> bool {
+    self.key0 & 0x0C == 0x04
+  }
   pub fn read(&self, addr: u16) -> u8 {
-    self.rom[addr as usize]
+    match addr {
+      0xFF4C => 0xFF,
+      // Bit 0 is set once the boot ROM is disabled, which can't be undone.
+      0xFF50 => 0xFE | !self.active as u8,
+      _      => self.rom[addr as usize],
+    }
+  }
+  pub fn write(&mut self, addr: u16, val: u8) {
+    match addr {
+      // KEY0 is locked once the boot ROM is disabled.
+      0xFF4C => if self.active {
+        self.key0 = val;
+      },
+      _      => self.active &= val == 0,
+    }
   }
-  pub fn write(&mut self, _: u16, val: u8) {
-    self.active &= val == 0;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn disabling_cant_be_undone() {
+    let mut bootrom = Bootrom::new();
+    assert_eq!(bootrom.read(0xFF50), 0xFE);
+    bootrom.write(0xFF50, 0x00);
+    assert!(bootrom.is_active());
+    bootrom.write(0xFF50, 0x01);
+    assert!(!bootrom.is_active());
+    assert_eq!(bootrom.read(0xFF50), 0xFF);
+    bootrom.write(0xFF50, 0x00);
+    assert!(!bootrom.is_active());
+    assert_eq!(bootrom.read(0xFF50), 0xFF);
+    // KEY0 is locked along with it.
+    bootrom.write(0xFF4C, 0x04);
+    assert!(!bootrom.dmg_compat());
   }
 }
@@ -3,8 +3,57 @@ use std::str;
 use serde::{Deserialize, Serialize};
 
 use mbc::Mbc;
+pub use mbc::UnsupportedMbc;
+use compat::CompatPalettes;
 
 mod mbc;
+mod compat;
+
+fn cartridge_type_name(cartridge_type: u8) -> &'static str {
+  match cartridge_type {
+    0x00 => "ROM ONLY",
+    0x01 => "MBC1",
+    0x02 => "MBC1+RAM",
+    0x03 => "MBC1+RAM+BATTERY",
+    0x05 => "MBC2",
+    0x06 => "MBC2+BATTERY",
+    0x08 => "ROM+RAM",
+    0x09 => "ROM+RAM+BATTERY",
+    0x0B => "MMM01",
+    0x0C => "MMM01+RAM",
+    0x0D => "MMM01+RAM+BATTERY",
+    0x0F => "MBC3+TIMER+BATTERY",
+    0x10 => "MBC3+TIMER+RAM+BATTERY",
+    0x11 => "MBC3",
+    0x12 => "MBC3+RAM",
+    0x13 => "MBC3+RAM+BATTERY",
+    0x19 => "MBC5",
+    0x1A => "MBC5+RAM",
+    0x1B => "MBC5+RAM+BATTERY",
+    0x1C => "MBC5+RUMBLE",
+    0x1D => "MBC5+RUMBLE+RAM",
+    0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+    0x20 => "MBC6",
+    0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+    0xFC => "POCKET CAMERA",
+    0xFD => "BANDAI TAMA5",
+    0xFE => "HuC3",
+    0xFF => "HuC1+RAM+BATTERY",
+    _    => "UNKNOWN",
+  }
+}
+
+// (RAM, battery) for each cartridge type. MBC2 has RAM built into the MBC itself.
+fn cartridge_features(cartridge_type: u8) -> (bool, bool) {
+  match cartridge_type {
+    0x02 | 0x08 | 0x0C | 0x12 | 0x1A | 0x1D => (true, false),
+    0x05                                    => (true, false),
+    0x03 | 0x06 | 0x09 | 0x0D | 0x10 | 0x13 => (true, true),
+    0x1B | 0x1E | 0x22 | 0xFC | 0xFE | 0xFF => (true, true),
+    0x0F                                    => (false, true),
+    _                                       => (false, false),
+  }
+}
 
 #[repr(C)]
 pub struct CartridgeHeader {
@@ -27,15 +76,20 @@ pub struct CartridgeHeader {
 
 impl CartridgeHeader {
   fn new(data: [u8; 0x50]) -> Self {
-    let ret = unsafe {
+    let ret = Self::new_unchecked(data);
+    assert!(Self::checksum(&data) == ret.header_checksum[0], "Checksum validation failed.");
+    ret
+  }
+  fn new_unchecked(data: [u8; 0x50]) -> Self {
+    unsafe {
       std::mem::transmute::<[u8; 0x50], Self>(data)
-    };
-    let mut chksum: u8 = 0;
-    for i in 0x34..0x4d {
-      chksum = chksum.wrapping_sub(data[i]).wrapping_sub(1);
     }
-    assert!(chksum == ret.header_checksum[0], "Checksum validation failed.");
-    ret
+  }
+  fn checksum(data: &[u8; 0x50]) -> u8 {
+    data[0x34..0x4d].iter().fold(0u8, |acc, &b| acc.wrapping_sub(b).wrapping_sub(1))
+  }
+  fn cartridge_type_name(&self) -> &'static str {
+    cartridge_type_name(self.cartridge_type[0])
   }
   fn rom_size(&self) -> usize {
     assert!(self.rom_size[0] <= 0x08, "Invalid rom size {}.", self.rom_size[0]);
@@ -54,53 +108,274 @@ impl CartridgeHeader {
   }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CgbFlag {
+  Dmg,
+  CgbEnhanced,
+  CgbOnly,
+}
+
+impl CgbFlag {
+  fn new(flag: u8) -> Self {
+    match flag {
+      0x80 => Self::CgbEnhanced,
+      0xC0 => Self::CgbOnly,
+      _    => Self::Dmg,
+    }
+  }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CartridgeInfo {
+  pub title: String,
+  pub manufacturer: String,
+  pub cgb_flag: CgbFlag,
+  pub sgb: bool,
+  pub cartridge_type: String,
+  pub rom_size: usize,
+  pub ram_size: usize,
+  pub destination: u8,
+  pub version: u8,
+  pub header_checksum: u8,
+  pub global_checksum: u16,
+  pub checksum_valid: bool,
+}
+
+// The SRAM contents of a cartridge without a save.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RamInit {
+  Zero,
+  Ones,
+  // Deterministic pseudo-random garbage, e.g. after the battery died.
+  Random(u64),
+}
+
+impl RamInit {
+  pub(crate) fn generator(self) -> impl FnMut() -> u8 {
+    let mut state = if let RamInit::Random(seed) = self { seed } else { 0 };
+    move || match self {
+      RamInit::Zero      => 0x00,
+      RamInit::Ones      => 0xFF,
+      // splitmix64
+      RamInit::Random(_) => {
+        state = state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        ((z ^ (z >> 31)) >> 56) as u8
+      },
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BankInfo {
+  pub rom_low: usize,  // 0x0000-0x3FFF
+  pub rom_high: usize, // 0x4000-0x7FFF
+  pub ram: usize,      // 0xA000-0xBFFF
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Cartridge {
   pub title: String,
+  pub cgb_flag: CgbFlag,
   pub is_cgb: bool,
   rom: Vec<u8>,
   pub sram: Vec<u8>,
   mbc: Mbc,
+  dirty: bool,
 }
 
 impl Cartridge {
-  pub fn new(rom: Vec<u8>, save: Option<Vec<u8>>) -> Self {
+  pub fn new(rom: Vec<u8>, save: Option<Vec<u8>>) -> Result<Self, UnsupportedMbc> {
+    Self::new_with_ram_init(rom, save, RamInit::Zero)
+  }
+  // `init` decides the SRAM contents when there is no save.
+  pub fn new_with_ram_init(rom: Vec<u8>, save: Option<Vec<u8>>, init: RamInit) -> Result<Self, UnsupportedMbc> {
     let header = CartridgeHeader::new(rom[0x100..0x150].try_into().unwrap());
+    Self::from_header(header, rom, save, init)
+  }
+  // Loads homebrew and patched ROMs whose header checksum wasn't fixed, only warning about the mismatch.
+  // A cartridge type missing from the list is loaded as ROM ONLY, but known MBCs which aren't
+  // emulated are still rejected as in `new`.
+  pub fn new_lenient(rom: Vec<u8>, save: Option<Vec<u8>>) -> Result<Self, UnsupportedMbc> {
+    let data = rom[0x100..0x150].try_into().unwrap();
+    let mut header = CartridgeHeader::new_unchecked(data);
+    if CartridgeHeader::checksum(&data) != header.header_checksum[0] {
+      log::warn!("Header checksum mismatch, loading anyway.");
+    }
+    if header.cartridge_type_name() == "UNKNOWN" {
+      log::warn!("Unknown cartridge type {:02x}, loading as ROM ONLY.", header.cartridge_type[0]);
+      header.cartridge_type = [0x00];
+    }
+    Self::from_header(header, rom, save, RamInit::Zero)
+  }
+  fn from_header(header: CartridgeHeader, rom: Vec<u8>, save: Option<Vec<u8>>, init: RamInit) -> Result<Self, UnsupportedMbc> {
 
     let title = str::from_utf8(&header.title).unwrap().trim_end_matches('\0').to_string();
-    let is_cgb = header.cgb_flag[0] == 0x80 || header.cgb_flag[0] == 0xc0;
+    let cgb_flag = CgbFlag::new(header.cgb_flag[0]);
+    let is_cgb = cgb_flag != CgbFlag::Dmg;
     let rom_size = header.rom_size();
     let sram_size = header.sram_size();
     let rom_banks = rom_size >> 14; // ROMバンクは1つあたり16 KiB
-    let mbc = Mbc::new(header.cartridge_type[0], rom_banks);
-
-    // println!("cartridge info {{ title: {}, cgb: {}, type: {}, rom_size: {} B, sram_size: {} B }}",
-    //   title,
-    //   is_cgb,
-    //   match mbc {
-    //     Mbc::NoMbc { .. } => "NO MBC",
-    //     Mbc::Mbc1 { .. } => "MBC1",
-    //     Mbc::Mbc3 { .. } => "MBC3",
-    //     Mbc::Mbc5 { .. } => "MBC5",
-    //   },
-    //   rom_size,
-    //   sram_size,
-    // );
+    let mbc = Mbc::new(header.cartridge_type[0], rom_banks)?;
+
+    log::info!("cartridge info {{ title: {}, cgb: {}, type: {}, rom_size: {} B, sram_size: {} B }}",
+      title,
+      is_cgb,
+      header.cartridge_type_name(),
+      rom_size,
+      sram_size,
+    );
     assert!(
       rom.len() == rom_size,
       "Expected {} bytes of cartridge ROM, got {}", rom_size, rom.len()
     );
 
-    let sram = save.unwrap_or(vec![0; sram_size]);
+    let sram = save.unwrap_or_else(|| {
+      let mut next = init.generator();
+      (0..sram_size).map(|_| next()).collect()
+    });
     assert!(sram.len() == sram_size,
       "Expected {} bytes of save file, got {}", sram_size, sram.len()
     );
-    Self {
+    Ok(Self {
       title,
+      cgb_flag,
       is_cgb,
       rom,
       sram,
       mbc,
+      dirty: false,
+    })
+  }
+  // Checks what `new` would otherwise panic on. An empty `save` means there is none.
+  pub fn validate(rom: &[u8], save: &[u8]) -> Result<(), String> {
+    if rom.len() < 0x150 {
+      return Err(format!("Expected at least {} bytes of cartridge ROM, got {}", 0x150, rom.len()));
+    }
+    let data: &[u8; 0x50] = rom[0x100..0x150].try_into().unwrap();
+    if CartridgeHeader::checksum(data) != data[0x4d] {
+      return Err(String::from("Checksum validation failed."));
+    }
+    Mbc::new(data[0x47], 0).map_err(|e| e.to_string())?;
+    if data[0x48] > 0x08 {
+      return Err(format!("Invalid rom size {}.", data[0x48]));
+    }
+    if data[0x49] > 0x05 {
+      return Err(format!("Invalid sram size {}.", data[0x49]));
+    }
+    let rom_size = 1 << (15 + data[0x48]);
+    if rom.len() != rom_size {
+      return Err(format!("Expected {} bytes of cartridge ROM, got {}", rom_size, rom.len()));
+    }
+    let sram_size = CartridgeHeader::new_unchecked(*data).sram_size();
+    if !save.is_empty() && save.len() != sram_size {
+      return Err(format!("Expected {} bytes of save file, got {}", sram_size, save.len()));
+    }
+    Ok(())
+  }
+  // Returns the MBC to its power-on state, keeping ROM and SRAM.
+  pub fn reset(&mut self) {
+    let header = CartridgeHeader::new_unchecked(self.rom[0x100..0x150].try_into().unwrap());
+    // The type was already accepted by `new`, or is unknown and was loaded as ROM ONLY by `new_lenient`.
+    self.mbc = Mbc::new(header.cartridge_type[0], header.rom_size() >> 14).unwrap_or(Mbc::NoMbc);
+  }
+  pub fn info(&self) -> CartridgeInfo {
+    let header = CartridgeHeader::new_unchecked(self.rom[0x100..0x150].try_into().unwrap());
+    // The global checksum is the sum of all bytes except the checksum itself.
+    let sum = self.rom.iter().enumerate()
+      .filter(|&(i, _)| i != 0x14E && i != 0x14F)
+      .fold(0u16, |acc, (_, &b)| acc.wrapping_add(b as u16));
+    let global_checksum = u16::from_be_bytes(header.global_checksum);
+    CartridgeInfo {
+      title: self.title.clone(),
+      manufacturer: String::from_utf8_lossy(&header.maker).trim_end_matches('\0').to_string(),
+      cgb_flag: self.cgb_flag,
+      sgb: header.sgb_flag[0] == 0x03,
+      cartridge_type: header.cartridge_type_name().to_string(),
+      rom_size: header.rom_size(),
+      ram_size: header.sram_size(),
+      destination: header.destination[0],
+      version: header.game_version[0],
+      header_checksum: header.header_checksum[0],
+      global_checksum,
+      checksum_valid: sum == global_checksum,
+    }
+  }
+  // Derived from `Mbc::get_addr`, masked by the actual ROM and RAM sizes like `read` does.
+  pub fn current_banks(&self) -> BankInfo {
+    let ram = if self.sram.is_empty() {
+      0
+    } else {
+      (self.mbc.get_addr(0xa000) & (self.sram.len() - 1)) >> 13
+    };
+    BankInfo {
+      rom_low: (self.mbc.get_addr(0x0000) & (self.rom.len() - 1)) >> 14,
+      rom_high: (self.mbc.get_addr(0x4000) & (self.rom.len() - 1)) >> 14,
+      ram,
+    }
+  }
+  pub fn load_sram(&mut self, data: &[u8]) -> Result<(), String> {
+    if data.len() != self.sram.len() {
+      return Err(format!("Expected {} bytes of save file, got {}", self.sram.len(), data.len()));
+    }
+    self.sram.copy_from_slice(data);
+    Ok(())
+  }
+  // Read from the cartridge type, as the MBC alone doesn't tell e.g. ROM ONLY from ROM+RAM+BATTERY.
+  pub fn has_ram(&self) -> bool {
+    cartridge_features(self.rom[0x147]).0
+  }
+  // Whether SRAM survives power off, i.e. whether it is worth writing to a save file.
+  pub fn has_battery(&self) -> bool {
+    cartridge_features(self.rom[0x147]).1
+  }
+  // Checksums of the whole ROM, as listed by ROM databases such as No-Intro.
+  pub fn rom_crc32(&self) -> u32 {
+    crc32fast::hash(&self.rom)
+  }
+  pub fn rom_sha1(&self) -> [u8; 20] {
+    sha1_smol::Sha1::from(&self.rom).digest().bytes()
+  }
+  pub fn title_hash(&self) -> u8 {
+    compat::title_hash(&self.rom)
+  }
+  // Palettes which the CGB boot ROM would assign when running this cartridge as a DMG game.
+  pub fn compat_palettes(&self) -> CompatPalettes {
+    compat::palettes(&self.rom)
+  }
+  pub fn sram_bytes(&self) -> &[u8] {
+    &self.sram
+  }
+  // Returns whether SRAM has been written since the last call.
+  pub fn take_dirty(&mut self) -> bool {
+    std::mem::replace(&mut self.dirty, false)
+  }
+  // Reads SRAM even when it is disabled.
+  pub fn peek(&self, addr: u16) -> u8 {
+    match addr {
+      0xa000..=0xbfff => if self.sram.is_empty() {
+        0xff
+      } else {
+        self.sram[self.mbc.get_addr(addr) & (self.sram.len() - 1)]
+      },
+      _               => self.read(addr),
+    }
+  }
+  // Patches the currently mapped ROM or SRAM byte instead of writing to the MBC.
+  pub fn poke(&mut self, addr: u16, val: u8) {
+    match addr {
+      0x0000..=0x7fff => {
+        let i = self.mbc.get_addr(addr) & (self.rom.len() - 1);
+        self.rom[i] = val;
+      },
+      0xa000..=0xbfff => if !self.sram.is_empty() {
+        let i = self.mbc.get_addr(addr) & (self.sram.len() - 1);
+        self.sram[i] = val;
+        self.dirty = true;
+      },
+      _               => unreachable!(),
     }
   }
   pub fn read(&self, addr: u16) -> u8 {
@@ -134,19 +409,185 @@ impl Cartridge {
     match addr {
       0x0000..=0x7fff => self.mbc.write(addr, val),
       0xa000..=0xbfff => match self.mbc {
-        Mbc::NoMbc => self.sram[addr as usize & (sram_len - 1)] = val,
+        Mbc::NoMbc => {
+          self.sram[addr as usize & (sram_len - 1)] = val;
+          self.dirty = true;
+        },
         Mbc::Mbc1 { ref sram_enable, .. } => if *sram_enable {
           self.sram[self.mbc.get_addr(addr) & (sram_len - 1)] = val;
+          self.dirty = true;
         },
         Mbc::Mbc3 { ref sram_enable, ref rtc_mode, .. } => if *rtc_mode {
         } else if *sram_enable {
           self.sram[self.mbc.get_addr(addr) & (sram_len - 1)] = val;
+          self.dirty = true;
         },
         Mbc::Mbc5 { ref sram_enable, .. } => if *sram_enable {
           self.sram[self.mbc.get_addr(addr) & (sram_len - 1)] = val;
+          self.dirty = true;
         },
       },
       _               => unreachable!(),
     }
   }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+
+  // A blank ROM with a valid header. `rom_size` and `sram_size` are the header codes.
+  pub fn rom(cartridge_type: u8, rom_size: u8, sram_size: u8) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000 << rom_size];
+    rom[0x147] = cartridge_type;
+    rom[0x148] = rom_size;
+    rom[0x149] = sram_size;
+    fix_checksum(&mut rom);
+    rom
+  }
+
+  pub fn fix_checksum(rom: &mut [u8]) {
+    rom[0x14D] = CartridgeHeader::checksum(rom[0x100..0x150].try_into().unwrap());
+  }
+
+  fn cartridge_with_cgb_flag(flag: u8) -> Cartridge {
+    let mut rom = rom(0x00, 0, 0);
+    rom[0x143] = flag;
+    fix_checksum(&mut rom);
+    Cartridge::new(rom, None).unwrap()
+  }
+
+  #[test]
+  fn cgb_flag() {
+    let dmg = cartridge_with_cgb_flag(0x00);
+    assert_eq!((dmg.cgb_flag, dmg.is_cgb), (CgbFlag::Dmg, false));
+    let enhanced = cartridge_with_cgb_flag(0x80);
+    assert_eq!((enhanced.cgb_flag, enhanced.is_cgb), (CgbFlag::CgbEnhanced, true));
+    let only = cartridge_with_cgb_flag(0xC0);
+    assert_eq!((only.cgb_flag, only.is_cgb), (CgbFlag::CgbOnly, true));
+  }
+
+  #[test]
+  fn lenient_loading_accepts_what_new_rejects() {
+    let mut bad_checksum = rom(0x00, 0, 0);
+    bad_checksum[0x14D] ^= 1;
+    assert!(Cartridge::validate(&bad_checksum, &[]).is_err());
+    assert!(Cartridge::new_lenient(bad_checksum, None).is_ok());
+
+    let mut unknown = rom(0x30, 0, 0);
+    unknown[0x150] = 0x42;
+    assert!(Cartridge::new(unknown.clone(), None).is_err());
+    let mut cartridge = Cartridge::new_lenient(unknown, None).unwrap();
+    assert_eq!(cartridge.read(0x0150), 0x42);
+    cartridge.reset();
+    assert_eq!(cartridge.current_banks().rom_high, 1);
+
+    assert!(Cartridge::new_lenient(rom(0x05, 0, 0), None).is_err());
+  }
+  #[test]
+  fn ram_and_battery_from_the_cartridge_type() {
+    let rom_ram_battery = Cartridge::new(rom(0x09, 0, 2), None).unwrap();
+    assert_eq!((rom_ram_battery.has_ram(), rom_ram_battery.has_battery()), (true, true));
+    let rom_only = Cartridge::new(rom(0x00, 0, 0), None).unwrap();
+    assert_eq!((rom_only.has_ram(), rom_only.has_battery()), (false, false));
+    assert_eq!(cartridge_features(0x05), (true, false));
+    assert_eq!(cartridge_features(0x0F), (false, true));
+  }
+  // Each bank starts with its number, little endian.
+  fn numbered_rom(cartridge_type: u8, rom_size: u8) -> Vec<u8> {
+    let mut rom = rom(cartridge_type, rom_size, 0);
+    for bank in 1..rom.len() >> 14 {
+      rom[bank << 14..(bank << 14) + 2].copy_from_slice(&(bank as u16).to_le_bytes());
+    }
+    rom
+  }
+
+  fn high_bank(cartridge: &Cartridge) -> u16 {
+    u16::from_le_bytes([cartridge.read(0x4000), cartridge.read(0x4001)])
+  }
+
+  #[test]
+  fn mbc5_bank_300_of_8_mib() {
+    let mut cartridge = Cartridge::new(numbered_rom(0x19, 0x08), None).unwrap();
+    cartridge.write(0x2000, (300 & 0xFF) as u8);
+    cartridge.write(0x3000, 1);
+    assert_eq!(high_bank(&cartridge), 300);
+    // Bit 8 is kept when only the low byte is written.
+    cartridge.write(0x2000, 0xFF);
+    assert_eq!(high_bank(&cartridge), 511);
+    cartridge.write(0x3000, 0);
+    assert_eq!(high_bank(&cartridge), 255);
+  }
+
+  #[test]
+  fn mbc5_bank_masked_by_rom_size() {
+    let mut cartridge = Cartridge::new(numbered_rom(0x19, 0x06), None).unwrap();
+    cartridge.write(0x2000, (300 & 0xFF) as u8);
+    cartridge.write(0x3000, 1);
+    assert_eq!(high_bank(&cartridge), 300 % 128);
+  }
+  #[test]
+  fn mbc3_bank_127_of_2_mib() {
+    let rom = numbered_rom(0x13, 0x06);
+    let mut cartridge = Cartridge::new(rom.clone(), None).unwrap();
+    cartridge.write(0x2000, 0x7F);
+    assert_eq!(high_bank(&cartridge), 127);
+    assert_eq!(cartridge.current_banks().rom_high, 127);
+    assert!((0..0x4000).all(|addr| cartridge.read(addr) == rom[addr as usize]));
+  }
+  #[test]
+  fn current_banks_follow_banking_writes() {
+    let mut cartridge = Cartridge::new(numbered_rom(0x03, 0x05), None).unwrap();
+    let low_bank = |cartridge: &Cartridge| u16::from_le_bytes([cartridge.read(0x0000), cartridge.read(0x0001)]);
+    assert_eq!(cartridge.current_banks(), BankInfo { rom_low: 0, rom_high: 1, ram: 0 });
+    cartridge.write(0x2000, 0x05);
+    cartridge.write(0x4000, 0x01);
+    assert_eq!(cartridge.current_banks(), BankInfo { rom_low: 0, rom_high: 0x25, ram: 0 });
+    assert_eq!(high_bank(&cartridge), 0x25);
+    // In mode 1 the upper bits also bank 0x0000-0x3FFF.
+    cartridge.write(0x6000, 0x01);
+    let banks = cartridge.current_banks();
+    assert_eq!(banks, BankInfo { rom_low: 0x20, rom_high: 0x25, ram: 0 });
+    assert_eq!(low_bank(&cartridge) as usize, banks.rom_low);
+  }
+
+  #[test]
+  fn current_ram_bank_follows_banking_writes() {
+    let mut cartridge = Cartridge::new(rom(0x13, 0x00, 0x03), None).unwrap();
+    cartridge.write(0x0000, 0x0A);
+    for bank in 0..4 {
+      cartridge.write(0x4000, bank);
+      cartridge.write(0xA000, bank + 0x10);
+    }
+    for bank in 0..4 {
+      cartridge.write(0x4000, bank);
+      assert_eq!(cartridge.current_banks().ram, bank as usize);
+      assert_eq!(cartridge.read(0xA000), bank + 0x10);
+    }
+  }
+  #[test]
+  fn sram_writes_set_the_dirty_flag() {
+    let mut cartridge = Cartridge::new(rom(0x03, 0x00, 0x02), Some(vec![0x11; 0x2000])).unwrap();
+    assert_eq!(cartridge.sram_bytes(), [0x11; 0x2000]);
+    assert!(!cartridge.take_dirty());
+    // Disabled SRAM ignores the write.
+    cartridge.write(0xA000, 0x22);
+    assert!(!cartridge.take_dirty());
+    cartridge.write(0x0000, 0x0A);
+    cartridge.write(0xA000, 0x22);
+    assert!(cartridge.take_dirty());
+    assert!(!cartridge.take_dirty());
+    assert_eq!(cartridge.sram_bytes()[0], 0x22);
+    assert!(cartridge.load_sram(&[0; 0x800]).is_err());
+    cartridge.load_sram(&[0x33; 0x2000]).unwrap();
+    assert_eq!(cartridge.read(0xA000), 0x33);
+  }
+  #[test]
+  fn random_ram_init_is_reproducible() {
+    let sram = |init| Cartridge::new_with_ram_init(rom(0x03, 0x00, 0x02), None, init).unwrap().sram;
+    assert_eq!(sram(RamInit::Random(7)), sram(RamInit::Random(7)));
+    assert_ne!(sram(RamInit::Random(7)), sram(RamInit::Random(8)));
+    assert_eq!(sram(RamInit::Ones), vec![0xFF; 0x2000]);
+    assert_eq!(sram(RamInit::Zero), vec![0x00; 0x2000]);
+  }
+}
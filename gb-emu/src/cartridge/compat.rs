@@ -0,0 +1,210 @@
+// Default palettes which the CGB boot ROM assigns to DMG cartridges.
+// The tables are taken from the CGB boot ROM.
+const CHECKSUMS: [u8; 94] = [
+  0x00, 0x88, 0x16, 0x36, 0xd1, 0xdb, 0xf2, 0x3c, 0x8c, 0x92, 0x3d, 0x5c, 0x58, 0xc9, 0x3e, 0x70,
+  0x1d, 0x59, 0x69, 0x19, 0x35, 0xa8, 0x14, 0xaa, 0x75, 0x95, 0x99, 0x34, 0x6f, 0x15, 0xff, 0x97,
+  0x4b, 0x90, 0x17, 0x10, 0x39, 0xf7, 0xf6, 0xa2, 0x49, 0x4e, 0x43, 0x68, 0xe0, 0x8b, 0xf0, 0xce,
+  0x0c, 0x29, 0xe8, 0xb7, 0x86, 0x9a, 0x52, 0x01, 0x9d, 0x71, 0x9c, 0xbd, 0x5d, 0x6d, 0x67, 0x3f,
+  0x6b, 0xb3, 0x46, 0x28, 0xa5, 0xc6, 0xd3, 0x27, 0x61, 0x18, 0x66, 0x6a, 0xbf, 0x0d, 0xf4, 0xb3,
+  0x46, 0x28, 0xa5, 0xc6, 0xd3, 0x27, 0x61, 0x18, 0x66, 0x6a, 0xbf, 0x0d, 0xf4, 0xb3,
+];
+
+// The first entry of `CHECKSUMS` which needs the 4th letter of the title to be identified.
+const FIRST_DUPLICATE: usize = 0x41;
+
+const FOURTH_LETTERS: [u8; 29] = *b"BEFAARBEKEK R-URAR INAILICE R";
+
+// Bit 7 means that the game requires the DMG boot tilemap.
+const PALETTE_INDICES: [u8; 94] = [
+  0x00, 0x04, 0x05, 0x23, 0x22, 0x03, 0x1f, 0x0f, 0x0a, 0x05, 0x13, 0x24, 0x87, 0x25, 0x1e, 0x2c,
+  0x15, 0x20, 0x1f, 0x14, 0x05, 0x21, 0x0d, 0x0e, 0x05, 0x1d, 0x05, 0x12, 0x09, 0x03, 0x02, 0x1a,
+  0x19, 0x19, 0x29, 0x2a, 0x1a, 0x2d, 0x2a, 0x2d, 0x24, 0x26, 0x9a, 0x2a, 0x1e, 0x29, 0x22, 0x22,
+  0x05, 0x2a, 0x06, 0x05, 0x21, 0x19, 0x2a, 0x2a, 0x28, 0x02, 0x10, 0x19, 0x2a, 0x2a, 0x05, 0x00,
+  0x27, 0x24, 0x16, 0x19, 0x06, 0x20, 0x0c, 0x24, 0x0b, 0x27, 0x12, 0x27, 0x18, 0x1f, 0x32, 0x11,
+  0x2e, 0x06, 0x1b, 0x00, 0x2f, 0x29, 0x29, 0x00, 0x00, 0x13, 0x22, 0x17, 0x12, 0x1d,
+];
+
+// Byte offsets of OBJ0, OBJ1 and BG palettes in `PALETTES`.
+const COMBINATIONS: [[u8; 3]; 51] = [
+  [0x20, 0x20, 0xe8],
+  [0x90, 0x90, 0x90],
+  [0xa0, 0xa0, 0xa0],
+  [0xc0, 0xc0, 0xc0],
+  [0x48, 0x48, 0x48],
+  [0x00, 0x00, 0x00],
+  [0xd8, 0xd8, 0xd8],
+  [0x28, 0x28, 0x28],
+  [0x60, 0x60, 0x60],
+  [0xd0, 0xd0, 0xd0],
+  [0x80, 0x40, 0x40],
+  [0x20, 0xe0, 0xe0],
+  [0x20, 0x10, 0x10],
+  [0x18, 0x20, 0x20],
+  [0x20, 0xe8, 0xe8],
+  [0xe0, 0x20, 0xe0],
+  [0x10, 0x88, 0x10],
+  [0x80, 0x80, 0x40],
+  [0x20, 0x20, 0x38],
+  [0x20, 0x20, 0x90],
+  [0x20, 0x20, 0xa0],
+  [0x98, 0x98, 0x48],
+  [0x1e, 0x1e, 0x58],
+  [0x88, 0x88, 0x10],
+  [0x20, 0x20, 0x10],
+  [0x20, 0x20, 0x18],
+  [0xe0, 0xe0, 0x00],
+  [0x18, 0x18, 0x00],
+  [0x00, 0x00, 0x08],
+  [0x90, 0xb0, 0x90],
+  [0xa0, 0xb0, 0xa0],
+  [0xc0, 0xb0, 0xc0],
+  [0x80, 0xb0, 0x40],
+  [0x88, 0x20, 0x68],
+  [0xde, 0x00, 0x70],
+  [0xde, 0x20, 0x78],
+  [0x98, 0xb6, 0x48],
+  [0x80, 0xe0, 0x50],
+  [0x20, 0xb8, 0xe0],
+  [0x88, 0xb0, 0x10],
+  [0x20, 0x00, 0x10],
+  [0x20, 0xe0, 0x18],
+  [0xe0, 0x18, 0x00],
+  [0x18, 0xe0, 0x20],
+  [0xa8, 0xe0, 0x20],
+  [0x18, 0xe0, 0x00],
+  [0xc8, 0x18, 0xe0],
+  [0x00, 0xe0, 0x40],
+  [0x20, 0x18, 0xe0],
+  [0xe0, 0x18, 0x30],
+  [0x20, 0xe0, 0xe8],
+];
+
+const PALETTES: [u16; 120] = [
+  0x7fff, 0x32bf, 0x00d0, 0x0000,
+  0x639f, 0x4279, 0x15b0, 0x04cb,
+  0x7fff, 0x6e31, 0x454a, 0x0000,
+  0x7fff, 0x1bef, 0x0200, 0x0000,
+  0x7fff, 0x421f, 0x1cf2, 0x0000,
+  0x7fff, 0x5294, 0x294a, 0x0000,
+  0x7fff, 0x03ff, 0x012f, 0x0000,
+  0x7fff, 0x03ef, 0x01d6, 0x0000,
+  0x7fff, 0x42b5, 0x3dc8, 0x0000,
+  0x7e74, 0x03ff, 0x0180, 0x0000,
+  0x67ff, 0x77ac, 0x1a13, 0x2d6b,
+  0x7ed6, 0x4bff, 0x2175, 0x0000,
+  0x53ff, 0x4a5f, 0x7e52, 0x0000,
+  0x4fff, 0x7ed2, 0x3a4c, 0x1ce0,
+  0x03ed, 0x7fff, 0x255f, 0x0000,
+  0x036a, 0x021f, 0x03ff, 0x7fff,
+  0x7fff, 0x01df, 0x0112, 0x0000,
+  0x231f, 0x035f, 0x00f2, 0x0009,
+  0x7fff, 0x03ea, 0x011f, 0x0000,
+  0x299f, 0x001a, 0x000c, 0x0000,
+  0x7fff, 0x027f, 0x001f, 0x0000,
+  0x7fff, 0x03e0, 0x0206, 0x0120,
+  0x7fff, 0x7eeb, 0x001f, 0x7c00,
+  0x7fff, 0x3fff, 0x7e00, 0x001f,
+  0x7fff, 0x03ff, 0x001f, 0x0000,
+  0x03ff, 0x001f, 0x000c, 0x0000,
+  0x7fff, 0x033f, 0x0193, 0x0000,
+  0x0000, 0x4200, 0x037f, 0x7fff,
+  0x7fff, 0x7e8c, 0x7c00, 0x0000,
+  0x7fff, 0x1bef, 0x6180, 0x0000,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompatPalettes {
+  pub bg: [u16; 4],
+  pub obj0: [u16; 4],
+  pub obj1: [u16; 4],
+}
+
+pub fn title_hash(rom: &[u8]) -> u8 {
+  rom[0x134..=0x143].iter().fold(0u8, |acc, &x| acc.wrapping_add(x))
+}
+
+fn is_licensed_by_nintendo(rom: &[u8]) -> bool {
+  match rom[0x14B] {
+    0x01 => true,
+    0x33 => &rom[0x144..=0x145] == b"01",
+    _    => false,
+  }
+}
+
+fn palette_index(rom: &[u8]) -> usize {
+  if !is_licensed_by_nintendo(rom) {
+    return 0;
+  }
+  let hash = title_hash(rom);
+  for (i, &checksum) in CHECKSUMS.iter().enumerate() {
+    if checksum != hash {
+      continue;
+    }
+    if i < FIRST_DUPLICATE || FOURTH_LETTERS[i - FIRST_DUPLICATE] == rom[0x137] {
+      return (PALETTE_INDICES[i] & 0x7F) as usize;
+    }
+  }
+  0
+}
+
+fn palette_at(offset: u8) -> [u16; 4] {
+  // Some combinations point into the middle of a palette.
+  let start = offset as usize >> 1;
+  PALETTES[start..start + 4].try_into().unwrap()
+}
+
+pub fn palettes(rom: &[u8]) -> CompatPalettes {
+  let [obj0, obj1, bg] = COMBINATIONS[palette_index(rom)];
+  CompatPalettes {
+    bg: palette_at(bg),
+    obj0: palette_at(obj0),
+    obj1: palette_at(obj1),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn rom(title: &[u8], licensee: u8) -> Vec<u8> {
+    let mut rom = vec![0; 0x150];
+    rom[0x134..0x134 + title.len()].copy_from_slice(title);
+    rom[0x14B] = licensee;
+    rom
+  }
+
+  #[test]
+  fn title_hash_of_known_titles() {
+    assert_eq!(title_hash(&rom(b"TETRIS", 0x01)), 0xDB);
+    assert_eq!(title_hash(&rom(b"POKEMON RED", 0x01)), 0x14);
+  }
+
+  #[test]
+  fn known_titles() {
+    assert_eq!(palette_index(&rom(b"TETRIS", 0x01)), 0x03);
+    assert_eq!(palette_index(&rom(b"POKEMON RED", 0x01)), 0x0D);
+    // These share a hash, and are told apart by the 4th letter.
+    assert_eq!(palette_index(&rom(b"SUPER MARIOLAND", 0x01)), 0x16);
+    assert_eq!(palette_index(&rom(b"SUPRE MARIOLAND", 0x01)), 0x2E);
+    assert_eq!(palette_index(&rom(b"SUEPR MARIOLAND", 0x01)), 0);
+  }
+
+  #[test]
+  fn new_licensee_code() {
+    let mut rom = rom(b"TETRIS", 0x33);
+    assert_eq!(palette_index(&rom), 0);
+    rom[0x144..=0x145].copy_from_slice(b"01");
+    assert_eq!(palette_index(&rom), 0x03);
+  }
+
+  #[test]
+  fn other_licensees_get_the_default() {
+    let rom = rom(b"TETRIS", 0x00);
+    assert_eq!(palette_index(&rom), 0);
+    assert_eq!(palettes(&rom), CompatPalettes {
+      bg: palette_at(0xe8),
+      obj0: palette_at(0x20),
+      obj1: palette_at(0x20),
+    });
+  }
+}
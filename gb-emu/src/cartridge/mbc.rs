@@ -1,5 +1,19 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnsupportedMbc {
+  pub cartridge_type: u8,
+  pub name: &'static str,
+}
+
+impl fmt::Display for UnsupportedMbc {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Not supported: {} ({:02x})", self.name, self.cartridge_type)
+  }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Mbc {
   NoMbc,
@@ -27,8 +41,8 @@ pub enum Mbc {
 }
 
 impl Mbc {
-  pub fn new(cartridge_type: u8, rom_banks: usize) -> Self {
-    match cartridge_type {
+  pub fn new(cartridge_type: u8, rom_banks: usize) -> Result<Self, UnsupportedMbc> {
+    Ok(match cartridge_type {
       0x00 | 0x08 | 0x09 => Self::NoMbc,
       0x01..=0x03        => Self::Mbc1 {
         sram_enable: false,
@@ -51,8 +65,11 @@ impl Mbc {
         high_bank: 0b00,
         rom_banks,
       },
-      _                  => panic!("Not supported: {:02x}", cartridge_type),
-    }
+      _                  => return Err(UnsupportedMbc {
+        cartridge_type,
+        name: super::cartridge_type_name(cartridge_type),
+      }),
+    })
   }
   pub fn write(&mut self, addr: u16, val: u8) {
     match self {
@@ -83,7 +100,8 @@ impl Mbc {
         ..
       } => match addr {
         0x0000..=0x1fff => *sram_enable = val & 0xf == 0xa,
-        0x2000..=0x3fff => *low_bank = if val == 0 {
+        // 7-bit ROM bank number. 0 is mapped to 1 after masking, so 0x80 selects bank 1 too.
+        0x2000..=0x3fff => *low_bank = if val & 0x7f == 0 {
           1
         } else {
           (val & 0x7f) as usize
@@ -104,6 +122,7 @@ impl Mbc {
         ..
       } => match addr {
         0x0000..=0x1fff => *sram_enable = val & 0xf == 0xa,
+        // 9-bit ROM bank number. It is masked by the actual number of banks in `get_addr`.
         0x2000..=0x2fff => *low_bank = (*low_bank & 0x100) | val as usize,
         0x3000..=0x3fff => *low_bank = (*low_bank & 0x0ff) | ((val as usize & 1) << 8),
         0x4000..=0x5fff => *high_bank = (val & 0xf) as usize,
@@ -4,13 +4,13 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
   cpu::{
-    register::Registers,
     interrupts::{Interrupts, VBLANK, STAT, TIMER, SERIAL, JOYPAD},
   },
   peripherals::Peripherals,
 };
 
 mod register;
+pub use register::Registers;
 mod operand;
 mod fetch;
 mod decode;
@@ -42,6 +42,8 @@ struct Ctx {
   opcode: u8,
   cb: bool,
   int: bool,
+  boundary: bool,
+  breakpoint: bool,
   cache: HashMap<String, Cache>,
 }
 
@@ -50,6 +52,15 @@ pub struct Cpu {
   regs: Registers,
   pub interrupts: Interrupts,
   ctx: Ctx,
+  // Executions per opcode, base opcodes first and then CB-prefixed ones.
+  #[serde(skip)]
+  coverage: Option<Box<[u64; 512]>>,
+}
+
+impl Default for Cpu {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Cpu {
@@ -70,9 +81,71 @@ impl Cpu {
       regs: Registers::default(),
       interrupts: Interrupts::default(),
       ctx,
+      coverage: None,
+    }
+  }
+  // Sets the registers to the values the boot ROM leaves behind.
+  pub fn init_post_boot(&mut self, is_cgb: bool) {
+    let coverage = self.coverage.take();
+    *self = Self::new();
+    self.coverage = coverage;
+    if is_cgb {
+      self.regs.write_af(0x1180);
+      self.regs.write_bc(0x0000);
+      self.regs.write_de(0xFF56);
+      self.regs.write_hl(0x000D);
+    } else {
+      self.regs.write_af(0x01B0);
+      self.regs.write_bc(0x0013);
+      self.regs.write_de(0x00D8);
+      self.regs.write_hl(0x014D);
+    }
+    self.regs.sp = 0xFFFE;
+    self.regs.pc = 0x0100;
+  }
+  pub fn enable_opcode_coverage(&mut self) {
+    if self.coverage.is_none() {
+      self.coverage = Some(Box::new([0; 512]));
     }
   }
+  pub fn opcode_coverage(&self) -> [u64; 512] {
+    self.coverage.as_deref().copied().unwrap_or([0; 512])
+  }
+  fn count_opcode(&mut self, index: usize) {
+    if let Some(coverage) = self.coverage.as_mut() {
+      coverage[index] += 1;
+    }
+  }
+  // Whether HALT is waiting for an interrupt.
+  pub fn is_halted(&self) -> bool {
+    !self.ctx.int && !self.ctx.cb && self.ctx.opcode == 0x76 && self.ctx.cache["inst"].step == 1
+  }
+  // Whether the last cycle fetched the opcode of a new instruction.
+  pub fn at_instruction_boundary(&self) -> bool {
+    self.ctx.boundary
+  }
+  // Whether `LD B,B`, which test ROMs use as a software breakpoint, has been executed since the last call.
+  pub fn take_breakpoint_hit(&mut self) -> bool {
+    std::mem::take(&mut self.ctx.breakpoint)
+  }
+  // At an instruction boundary, `pc` points to the opcode of the instruction about to execute.
+  pub fn registers(&self) -> Registers {
+    let mut regs = self.regs;
+    regs.pc = regs.pc.wrapping_sub(1);
+    regs
+  }
+  // The inverse of `registers`: the instruction at `regs.pc` is fetched and runs next. Interrupts are kept.
+  pub fn set_state(&mut self, bus: &Peripherals, regs: Registers) {
+    let coverage = self.coverage.take();
+    let interrupts = std::mem::take(&mut self.interrupts);
+    *self = Self::new();
+    self.coverage = coverage;
+    self.interrupts = interrupts;
+    self.regs = regs;
+    self.fetch(bus);
+  }
   pub fn emulate_cycle(&mut self, bus: &mut Peripherals) {
+    self.ctx.boundary = false;
     if self.ctx.int {
       self.call_isr(bus);
     } else {
@@ -77,7 +77,10 @@ impl Cpu {
       0x1F => self.rra(bus),
       0x2F => self.cpl(bus),
       0x3F => self.ccf(bus),
-      0x40 => self.ld(bus, Reg8::B, Reg8::B),
+      0x40 => {
+        self.ctx.breakpoint = true;
+        self.ld(bus, Reg8::B, Reg8::B)
+      },
       0x50 => self.ld(bus, Reg8::D, Reg8::B),
       0x60 => self.ld(bus, Reg8::H, Reg8::B),
       0x70 => self.ld(bus, Indirect::HL, Reg8::B),
@@ -535,6 +538,7 @@ impl Cpu {
     if let Some(v) = self.read8(bus, Imm8) {
       self.ctx.opcode =  v;
       self.ctx.cb = true;
+      self.count_opcode(0x100 | v as usize);
       self.cb_decode(bus);
     }
   }
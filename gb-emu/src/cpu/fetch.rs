@@ -11,6 +11,8 @@ impl Cpu {
     } else {
       self.regs.pc = self.regs.pc.wrapping_add(1);
       self.ctx.int = false;
+      self.ctx.boundary = true;
+      self.count_opcode(self.ctx.opcode as usize);
     }
     self.ctx.cb = false;
   }
@@ -306,11 +306,12 @@ impl Cpu {
   where Self: IO8<S> {
     step!(self.ctx.cache["inst"].step, (), {
       0: if let Some(v) = self.read8(bus, src) {
-        self.regs.set_zf(v & 0x7f == 0);
+        let new_val = v << 1;
+        self.regs.set_zf(new_val == 0);
         self.regs.set_nf(false);
         self.regs.set_hf(false);
         self.regs.set_cf(v & 0x80 > 0);
-        self.ctx.cache.get_mut("inst").unwrap().val8 = v << 1;
+        self.ctx.cache.get_mut("inst").unwrap().val8 = new_val;
         go!(self.ctx.cache.get_mut("inst").unwrap().step, 1);
       },
       1: if self.write8(bus, src, self.ctx.cache["inst"].val8).is_some() {
@@ -323,11 +324,12 @@ impl Cpu {
   where Self: IO8<S> {
     step!(self.ctx.cache["inst"].step, (), {
       0: if let Some(v) = self.read8(bus, src) {
-        self.regs.set_zf(v & 0xFE == 0);
+        let new_val = (v & 0x80) | (v >> 1);
+        self.regs.set_zf(new_val == 0);
         self.regs.set_nf(false);
         self.regs.set_hf(false);
         self.regs.set_cf(v & 1 > 0);
-        self.ctx.cache.get_mut("inst").unwrap().val8 = (v & 0x80) | (v >> 1);
+        self.ctx.cache.get_mut("inst").unwrap().val8 = new_val;
         go!(self.ctx.cache.get_mut("inst").unwrap().step, 1);
       },
       1: if self.write8(bus, src, self.ctx.cache["inst"].val8).is_some() {
@@ -340,11 +342,12 @@ impl Cpu {
   where Self: IO8<S> {
     step!(self.ctx.cache["inst"].step, (), {
       0: if let Some(v) = self.read8(bus, src) {
-        self.regs.set_zf(v & 0xFE == 0);
+        let new_val = v >> 1;
+        self.regs.set_zf(new_val == 0);
         self.regs.set_nf(false);
         self.regs.set_hf(false);
         self.regs.set_cf(v & 1 > 0);
-        self.ctx.cache.get_mut("inst").unwrap().val8 = v >> 1;
+        self.ctx.cache.get_mut("inst").unwrap().val8 = new_val;
         go!(self.ctx.cache.get_mut("inst").unwrap().step, 1);
       },
       1: if self.write8(bus, src, self.ctx.cache["inst"].val8).is_some() {
@@ -568,6 +571,8 @@ impl Cpu {
     self.interrupts.ime = false;
     self.fetch(bus);
   }
+  // IME is set after the next opcode is fetched, so a pending interrupt is dispatched
+  // only after the following instruction, and `EI; DI` never dispatches one.
   pub fn ei(&mut self, bus: &Peripherals) {
     self.fetch(bus);
     self.interrupts.ime = true;
@@ -587,6 +592,8 @@ impl Cpu {
   pub fn nop(&mut self, bus: &Peripherals) {
     self.fetch(bus);
   }
+  // Adjusts for the last addition or subtraction according to N, whichever instruction left it.
+  // So after CPL, which sets N and H, DAA adjusts as if for a subtraction, and after SCF/CCF for an addition.
   pub fn daa(&mut self, bus: &Peripherals) {
     let mut cf = false;
     if !self.regs.nf() {
@@ -598,6 +605,7 @@ impl Cpu {
         self.regs.a = self.regs.a.wrapping_add(0x06);
       }
     } else {
+      // Subtract 0x66, 0x60 or 0x06 depending on the borrows. The carry is kept as is.
       if self.regs.cf() {
         cf = true;
         if self.regs.hf() {
@@ -681,6 +689,7 @@ impl Cpu {
       },
     });
   }
+  // For POP AF, `write_af` clears the low nibble of F, which always reads 0.
   pub fn pop(&mut self, bus: &mut Peripherals, dst: Reg16) {
     if let Some(v) = self.pop16(bus) {
       self.write16(bus, dst, v);
@@ -726,6 +735,11 @@ impl Cpu {
   where Self: IO16<S> {
     step!(self.ctx.cache["inst"].step, (), {
       0: if let Some(v) = self.read16(bus, src) {
+        if (0xFE00..=0xFEFF).contains(&v) {
+          if let Some(row) = bus.ppu.oam_scan_row() {
+            bus.ppu.trigger_oam_corruption(row);
+          }
+        }
         self.ctx.cache.get_mut("inst").unwrap().val16 = v.wrapping_add(1);
         go!(self.ctx.cache.get_mut("inst").unwrap().step, 1);
       },
@@ -742,6 +756,11 @@ impl Cpu {
   where Self: IO16<S> {
     step!(self.ctx.cache["inst"].step, (), {
       0: if let Some(v) = self.read16(bus, src) {
+        if (0xFE00..=0xFEFF).contains(&v) {
+          if let Some(row) = bus.ppu.oam_scan_row() {
+            bus.ppu.trigger_oam_corruption(row);
+          }
+        }
         self.ctx.cache.get_mut("inst").unwrap().val16 = v.wrapping_sub(1);
         go!(self.ctx.cache.get_mut("inst").unwrap().step, 1);
       },
@@ -758,3 +777,257 @@ impl Cpu {
     panic!("Undefined opcode {:02x}", self.ctx.opcode);
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use crate::{
+    cpu::{interrupts::VBLANK, Cpu, Registers},
+    peripherals::Peripherals,
+    test_bus::TestBus,
+  };
+
+  const Z: u8 = 0b_1000_0000;
+  const N: u8 = 0b_0100_0000;
+  const H: u8 = 0b_0010_0000;
+  const C: u8 = 0b_0001_0000;
+
+  struct Machine {
+    cpu: Cpu,
+    bus: TestBus,
+    peripherals: Peripherals,
+  }
+
+  impl Machine {
+    fn new() -> Self {
+      let bus = TestBus::new();
+      Self {
+        cpu: Cpu::new(),
+        peripherals: TestBus::peripherals(bus.clone()),
+        bus,
+      }
+    }
+    // Runs `program` from 0x0100 and returns the registers at the first instruction after it.
+    fn run(&mut self, program: &[u8], regs: Registers) -> Registers {
+      let end = 0x0100 + program.len() as u16;
+      self.bus.mem.borrow_mut()[0x0100..end as usize].copy_from_slice(program);
+      self.cpu.set_state(&self.peripherals, Registers { pc: 0x0100, ..regs });
+      for _ in 0..1000 {
+        self.cpu.emulate_cycle(&mut self.peripherals);
+        if self.cpu.at_instruction_boundary() && self.cpu.registers().pc == end {
+          return self.cpu.registers();
+        }
+      }
+      panic!("the program didn't finish");
+    }
+  }
+
+  // DAA as specified by SameBoy, which adjusts the low nibble first. C is only ever set, never cleared.
+  fn reference_daa(a: u8, f: u8) -> (u8, u8) {
+    let mut result = a as i16;
+    if f & N > 0 {
+      if f & H > 0 {
+        result = (result - 0x06) & 0xFF;
+      }
+      if f & C > 0 {
+        result -= 0x60;
+      }
+    } else {
+      if f & H > 0 || result & 0x0F > 0x09 {
+        result += 0x06;
+      }
+      if f & C > 0 || result > 0x9F {
+        result += 0x60;
+      }
+    }
+    let mut flags = f & (N | C);
+    if result & 0xFF == 0 {
+      flags |= Z;
+    }
+    if result & 0x100 > 0 {
+      flags |= C;
+    }
+    (result as u8, flags)
+  }
+
+  #[test]
+  fn daa() {
+    let mut machine = Machine::new();
+    for a in 0..=0xFF {
+      for f in [0, N, H, C, N | H, N | C, H | C, N | H | C] {
+        let regs = machine.run(&[0x27], Registers { a, f, ..Default::default() });
+        assert_eq!((regs.a, regs.f), reference_daa(a, f), "A={:02x} F={:02x}", a, f);
+      }
+    }
+  }
+  // Runs the CB-prefixed `op` on B for every input, starting from both all flags clear and all set.
+  // `expected` gets B and the incoming carry.
+  fn check_shift(op: u8, expected: impl Fn(u8, bool) -> (u8, bool)) {
+    let mut machine = Machine::new();
+    for b in 0..=0xFF {
+      for f in [0, Z | N | H | C] {
+        let (result, carry) = expected(b, f & C > 0);
+        let flags = if result == 0 { Z } else { 0 } | if carry { C } else { 0 };
+        let regs = machine.run(&[0xCB, op], Registers { b, f, ..Default::default() });
+        assert_eq!((regs.b, regs.f), (result, flags), "CB {:02x} B={:02x} F={:02x}", op, b, f);
+      }
+    }
+  }
+
+  #[test]
+  fn rlc() {
+    check_shift(0x00, |b, _| (b.rotate_left(1), b & 0x80 > 0));
+  }
+
+  #[test]
+  fn rrc() {
+    check_shift(0x08, |b, _| (b.rotate_right(1), b & 1 > 0));
+  }
+
+  #[test]
+  fn rl() {
+    check_shift(0x10, |b, carry| (b << 1 | carry as u8, b & 0x80 > 0));
+  }
+
+  #[test]
+  fn rr() {
+    check_shift(0x18, |b, carry| (b >> 1 | (carry as u8) << 7, b & 1 > 0));
+  }
+
+  #[test]
+  fn sla() {
+    check_shift(0x20, |b, _| (b << 1, b & 0x80 > 0));
+  }
+
+  #[test]
+  fn sra() {
+    check_shift(0x28, |b, _| ((b as i8 >> 1) as u8, b & 1 > 0));
+  }
+
+  #[test]
+  fn srl() {
+    check_shift(0x38, |b, _| (b >> 1, b & 1 > 0));
+  }
+
+  #[test]
+  fn swap() {
+    check_shift(0x30, |b, _| (b.rotate_left(4), false));
+  }
+  // As in Mooneye's ei_timing, a VBlank interrupt is pending while IME is off.
+  fn pending_vblank(machine: &mut Machine) {
+    machine.cpu.interrupts.ime = false;
+    machine.cpu.interrupts.intr_flags = VBLANK;
+    machine.cpu.interrupts.intr_enable = VBLANK;
+  }
+
+  #[test]
+  fn ei_dispatches_after_the_next_instruction() {
+    let mut machine = Machine::new();
+    pending_vblank(&mut machine);
+    // EI; NOP; NOP
+    machine.bus.mem.borrow_mut()[0x0100..0x0103].copy_from_slice(&[0xFB, 0x00, 0x00]);
+    machine.cpu.set_state(&machine.peripherals, Registers { pc: 0x0100, sp: 0xD000, ..Default::default() });
+    for _ in 0..100 {
+      machine.cpu.emulate_cycle(&mut machine.peripherals);
+      if machine.cpu.at_instruction_boundary() && machine.cpu.registers().pc == 0x0040 {
+        // The first NOP ran, and the interrupt returns to the second.
+        let mem = machine.bus.mem.borrow();
+        assert_eq!(u16::from_le_bytes([mem[0xCFFE], mem[0xCFFF]]), 0x0102);
+        assert!(!machine.cpu.interrupts.ime);
+        assert_eq!(machine.cpu.interrupts.intr_flags, 0);
+        return;
+      }
+    }
+    panic!("the interrupt wasn't dispatched");
+  }
+
+  #[test]
+  fn ei_di_doesnt_dispatch() {
+    let mut machine = Machine::new();
+    pending_vblank(&mut machine);
+    // EI; DI; NOP
+    machine.run(&[0xFB, 0xF3, 0x00], Registers { sp: 0xD000, ..Default::default() });
+    assert_eq!(machine.cpu.interrupts.intr_flags, VBLANK);
+  }
+  // The A and F which CPL, SCF or CCF should leave.
+  fn flag_op(op: u8, a: u8, f: u8) -> (u8, u8) {
+    match op {
+      0x2F => (!a, f | N | H),
+      0x37 => (a, f & Z | C),
+      _    => (a, f & Z | !f & C),
+    }
+  }
+
+  #[test]
+  fn cpl_scf_ccf() {
+    let mut machine = Machine::new();
+    for op in [0x2F, 0x37, 0x3F] {
+      for a in [0x00, 0x5A, 0xFF] {
+        for f in (0..=0xF0).step_by(0x10) {
+          let regs = machine.run(&[op], Registers { a, f, ..Default::default() });
+          assert_eq!((regs.a, regs.f), flag_op(op, a, f), "{:02x} A={:02x} F={:02x}", op, a, f);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn daa_after_cpl_scf_ccf() {
+    let mut machine = Machine::new();
+    for op in [0x2F, 0x37, 0x3F] {
+      for a in 0..=0xFF {
+        for f in (0..=0xF0).step_by(0x10) {
+          let regs = machine.run(&[op, 0x27], Registers { a, f, ..Default::default() });
+          let (a2, f2) = flag_op(op, a, f);
+          assert_eq!((regs.a, regs.f), reference_daa(a2, f2), "{:02x} A={:02x} F={:02x}", op, a, f);
+        }
+      }
+    }
+  }
+  #[test]
+  fn pop_af_clears_the_low_bits_of_f() {
+    let mut machine = Machine::new();
+    machine.bus.mem.borrow_mut()[0xCFFE..0xD000].copy_from_slice(&0x1234u16.to_le_bytes());
+    let regs = machine.run(&[0xF1], Registers { sp: 0xCFFE, ..Default::default() });
+    assert_eq!((regs.a, regs.f, regs.sp), (0x12, 0x30, 0xD000));
+  }
+  #[test]
+  fn opcode_coverage_counts_each_execution() {
+    let mut machine = Machine::new();
+    assert_eq!(machine.cpu.opcode_coverage(), [0; 512]);
+    machine.cpu.enable_opcode_coverage();
+    // INC A; INC A; SWAP A
+    machine.run(&[0x3C, 0x3C, 0xCB, 0x37], Registers::default());
+    let mut expected = [0; 512];
+    expected[0x3C] = 2;
+    expected[0xCB] = 1;
+    expected[0x100 | 0x37] = 1;
+    // The NOP after the program was fetched when it stopped.
+    expected[0x00] = 1;
+    assert_eq!(machine.cpu.opcode_coverage(), expected);
+  }
+  #[test]
+  fn halted_until_an_interrupt_is_requested() {
+    let mut machine = Machine::new();
+    machine.cpu.interrupts.ime = false;
+    machine.cpu.interrupts.intr_enable = VBLANK;
+    // HALT; NOP
+    machine.bus.mem.borrow_mut()[0x0100..0x0102].copy_from_slice(&[0x76, 0x00]);
+    machine.cpu.set_state(&machine.peripherals, Registers { pc: 0x0100, ..Default::default() });
+    for _ in 0..3 {
+      machine.cpu.emulate_cycle(&mut machine.peripherals);
+    }
+    assert!(machine.cpu.is_halted());
+    for _ in 0..100 {
+      machine.cpu.emulate_cycle(&mut machine.peripherals);
+      assert!(machine.cpu.is_halted());
+    }
+    // With IME off, HALT ends without dispatching the interrupt.
+    machine.cpu.interrupts.intr_flags = VBLANK;
+    machine.cpu.emulate_cycle(&mut machine.peripherals);
+    while !machine.cpu.at_instruction_boundary() {
+      machine.cpu.emulate_cycle(&mut machine.peripherals);
+    }
+    assert!(!machine.cpu.is_halted());
+    assert_eq!(machine.cpu.registers().pc, 0x0101);
+  }
+}
@@ -22,7 +22,7 @@ impl Interrupts {
   }
   pub fn read(&self, addr: u16) -> u8 {
     match addr {
-      0xFF0F => self.intr_flags,
+      0xFF0F => self.intr_flags | 0xE0,
       0xFFFF => self.intr_enable,
       _      => unreachable!(),
     }
@@ -34,4 +34,18 @@ impl Interrupts {
       _      => unreachable!(),
     }
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn unused_flag_bits_read_as_1() {
+    let mut interrupts = Interrupts::default();
+    interrupts.write(0xFF0F, VBLANK | JOYPAD);
+    assert_eq!(interrupts.read(0xFF0F), 0xF1);
+    interrupts.write(0xFFFF, 0x1F);
+    assert_eq!(interrupts.read(0xFFFF), 0x1F);
+  }
+}
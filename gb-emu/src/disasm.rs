@@ -0,0 +1,130 @@
+// A disassembler for single SM83 instructions.
+// https://gbdev.io/gb-opcodes/optables/
+const R8: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const R16: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const R16_STACK: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const R16_MEM: [&str; 4] = ["(BC)", "(DE)", "(HL+)", "(HL-)"];
+const COND: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = ["ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP "];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+// Returns the instruction at `addr` and its length in bytes. Opcodes which don't exist are shown as data bytes.
+pub fn disassemble(read: impl Fn(u16) -> u8, addr: u16) -> (String, u16) {
+  let opcode = read(addr);
+  let n8 = || read(addr.wrapping_add(1));
+  let n16 = || u16::from_le_bytes([read(addr.wrapping_add(1)), read(addr.wrapping_add(2))]);
+  let jr = || addr.wrapping_add(2).wrapping_add(n8() as i8 as u16);
+  let x = opcode >> 6;
+  let y = (opcode >> 3 & 7) as usize;
+  let z = opcode & 7;
+  let p = y >> 1;
+  match (x, z) {
+    (0, 0) => match y {
+      0 => (String::from("NOP"), 1),
+      1 => (format!("LD (${:04X}),SP", n16()), 3),
+      2 => (String::from("STOP"), 2),
+      3 => (format!("JR ${:04X}", jr()), 2),
+      _ => (format!("JR {},${:04X}", COND[y - 4], jr()), 2),
+    },
+    (0, 1) => if y & 1 == 0 {
+      (format!("LD {},${:04X}", R16[p], n16()), 3)
+    } else {
+      (format!("ADD HL,{}", R16[p]), 1)
+    },
+    (0, 2) => if y & 1 == 0 {
+      (format!("LD {},A", R16_MEM[p]), 1)
+    } else {
+      (format!("LD A,{}", R16_MEM[p]), 1)
+    },
+    (0, 3) => (format!("{} {}", if y & 1 == 0 { "INC" } else { "DEC" }, R16[p]), 1),
+    (0, 4) => (format!("INC {}", R8[y]), 1),
+    (0, 5) => (format!("DEC {}", R8[y]), 1),
+    (0, 6) => (format!("LD {},${:02X}", R8[y], n8()), 2),
+    (0, _) => (String::from(["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"][y]), 1),
+    (1, 6) if y == 6 => (String::from("HALT"), 1),
+    (1, _) => (format!("LD {},{}", R8[y], R8[z as usize]), 1),
+    (2, _) => (format!("{}{}", ALU[y], R8[z as usize]), 1),
+    (_, 0) => match y {
+      0..=3 => (format!("RET {}", COND[y]), 1),
+      4 => (format!("LDH (${:02X}),A", n8()), 2),
+      5 => (format!("ADD SP,{}", n8() as i8), 2),
+      6 => (format!("LDH A,(${:02X})", n8()), 2),
+      _ => (format!("LD HL,SP{:+}", n8() as i8), 2),
+    },
+    (_, 1) => match y {
+      0 | 2 | 4 | 6 => (format!("POP {}", R16_STACK[p]), 1),
+      1 => (String::from("RET"), 1),
+      3 => (String::from("RETI"), 1),
+      5 => (String::from("JP HL"), 1),
+      _ => (String::from("LD SP,HL"), 1),
+    },
+    (_, 2) => match y {
+      0..=3 => (format!("JP {},${:04X}", COND[y], n16()), 3),
+      4 => (String::from("LD (C),A"), 1),
+      5 => (format!("LD (${:04X}),A", n16()), 3),
+      6 => (String::from("LD A,(C)"), 1),
+      _ => (format!("LD A,(${:04X})", n16()), 3),
+    },
+    (_, 3) => match y {
+      0 => (format!("JP ${:04X}", n16()), 3),
+      1 => {
+        let cb = n8();
+        let r = R8[cb as usize & 7];
+        let bit = cb >> 3 & 7;
+        match cb >> 6 {
+          0 => (format!("{} {}", ROT[bit as usize], r), 2),
+          1 => (format!("BIT {},{}", bit, r), 2),
+          2 => (format!("RES {},{}", bit, r), 2),
+          _ => (format!("SET {},{}", bit, r), 2),
+        }
+      },
+      6 => (String::from("DI"), 1),
+      7 => (String::from("EI"), 1),
+      _ => (format!("DB ${:02X}", opcode), 1),
+    },
+    (_, 4) => if y < 4 {
+      (format!("CALL {},${:04X}", COND[y], n16()), 3)
+    } else {
+      (format!("DB ${:02X}", opcode), 1)
+    },
+    (_, 5) => match y {
+      0 | 2 | 4 | 6 => (format!("PUSH {}", R16_STACK[p]), 1),
+      1 => (format!("CALL ${:04X}", n16()), 3),
+      _ => (format!("DB ${:02X}", opcode), 1),
+    },
+    (_, 6) => (format!("{}${:02X}", ALU[y], n8()), 2),
+    (_, _) => (format!("RST ${:02X}", y * 8), 1),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Disassembles `bytes` placed at `addr`.
+  fn disassemble_at(addr: u16, bytes: &[u8]) -> (String, u16) {
+    disassemble(|a| bytes.get(a.wrapping_sub(addr) as usize).copied().unwrap_or(0), addr)
+  }
+
+  #[test]
+  fn cb_prefixed() {
+    assert_eq!(disassemble_at(0x0150, &[0xCB, 0x37]), (String::from("SWAP A"), 2));
+    assert_eq!(disassemble_at(0x0150, &[0xCB, 0x7E]), (String::from("BIT 7,(HL)"), 2));
+    assert_eq!(disassemble_at(0x0150, &[0xCB, 0xC0]), (String::from("SET 0,B"), 2));
+  }
+
+  #[test]
+  fn imm16() {
+    assert_eq!(disassemble_at(0x0150, &[0x01, 0x34, 0x12]), (String::from("LD BC,$1234"), 3));
+    assert_eq!(disassemble_at(0x0150, &[0xCD, 0x00, 0x40]), (String::from("CALL $4000"), 3));
+    assert_eq!(disassemble_at(0x0150, &[0xEA, 0x00, 0xC0]), (String::from("LD ($C000),A"), 3));
+  }
+
+  #[test]
+  fn relative_jumps() {
+    // The offset is from the next instruction.
+    assert_eq!(disassemble_at(0x0150, &[0x18, 0xFE]), (String::from("JR $0150"), 2));
+    assert_eq!(disassemble_at(0x0150, &[0x20, 0x10]), (String::from("JR NZ,$0162"), 2));
+    assert_eq!(disassemble_at(0x0000, &[0x38, 0x80]), (String::from("JR C,$FF82"), 2));
+  }
+}
@@ -1,38 +1,440 @@
+use std::{collections::VecDeque, time::Duration};
+
 use serde::{Deserialize, Serialize};
 
 use crate::{
+  CPU_CLOCK_HZ,
   bootrom::Bootrom,
-  cartridge::Cartridge,
+  cartridge::{Cartridge, CgbFlag, RamInit},
   cpu::Cpu,
+  disasm,
+  joypad::Button,
+  movie::{Movie, MovieInput, Playback},
   peripherals::Peripherals,
 };
 
+// The number of instructions `step_back` can undo. Each snapshot is a full copy of the machine,
+// ROM included, so this costs up to 64 times (ROM size + about 100 KiB) while enabled.
+const MAX_INSTRUCTION_SNAPSHOTS: usize = 64;
+
+// M-cycles in a frame of 154 lines.
+const CYCLES_PER_FRAME: usize = 154 * 114;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CgbMode {
+  Auto,
+  // Run CGB-enhanced cartridges in DMG compatibility mode.
+  ForceDmg,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InitPattern {
+  Zero,
+  Ones,
+  // Deterministic pseudo-random garbage, like the RAM contents at power-on.
+  Seeded(u64),
+}
+
+impl InitPattern {
+  pub(crate) fn generator(self) -> impl FnMut() -> u8 {
+    match self {
+      InitPattern::Zero         => RamInit::Zero,
+      InitPattern::Ones         => RamInit::Ones,
+      InitPattern::Seeded(seed) => RamInit::Random(seed),
+    }.generator()
+  }
+}
+
+// Bump when a serialized field is removed, renamed or changes meaning, so that states saved by other builds are
+// rejected instead of misread. Added fields don't need a bump as long as they are `#[serde(default)]`.
+pub const STATE_VERSION: u32 = 1;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct GameBoy {
   pub cpu: Cpu,
   pub peripherals: Peripherals,
+  #[serde(skip)]
+  recording: Option<Movie>,
+  #[serde(skip)]
+  playback: Option<Playback>,
+  // Buttons pressed by `tap_button` and the vblanks left until they are released.
+  #[serde(default)]
+  taps: Vec<(Button, u32)>,
+  // The states at the start of the latest instructions, if `step_back` is enabled.
+  #[serde(skip)]
+  snapshots: Option<VecDeque<Box<GameBoy>>>,
 }
 
 impl GameBoy {
   pub fn new(cart_rom: &[u8], save: &[u8]) -> Self {
+    Self::new_forced(cart_rom, save, CgbMode::Auto)
+  }
+
+  // Same as `new`, but returns an error instead of panicking on a bad or unsupported ROM.
+  pub fn try_new(cart_rom: &[u8], save: &[u8]) -> Result<Self, String> {
+    Cartridge::validate(cart_rom, save)?;
+    Ok(Self::new(cart_rom, save))
+  }
+
+  pub fn new_forced(cart_rom: &[u8], save: &[u8], mode: CgbMode) -> Self {
     let cartridge = Cartridge::new(cart_rom.into(), if save.len() > 0 {
       Some(save.to_vec())
     } else {
       None
-    });
+    }).unwrap_or_else(|e| panic!("{}", e));
+    let is_cgb = cartridge.is_cgb && mode == CgbMode::Auto;
+    Self::boot(Bootrom::new(), cartridge, is_cgb)
+  }
+
+  // Boots a cartridge that was already parsed, e.g. a clone of one kept around to start many machines.
+  pub fn from_cartridge(cartridge: Cartridge) -> Self {
     let is_cgb = cartridge.is_cgb;
-    let bootrom = Bootrom::new();
-    let peripherals = Peripherals::new(bootrom, cartridge, is_cgb);
+    Self::boot(Bootrom::new(), cartridge, is_cgb)
+  }
+
+  // Fills WRAM, HRAM, VRAM and OAM with `pattern` instead of zeros.
+  pub fn new_with_init_pattern(cart_rom: &[u8], save: &[u8], pattern: InitPattern) -> Self {
+    let mut gameboy = Self::new(cart_rom, save);
+    gameboy.peripherals.fill_ram(&mut pattern.generator());
+    gameboy
+  }
+
+  // Fills the cartridge SRAM according to `init` when there is no save.
+  pub fn new_with_ram_init(cart_rom: &[u8], save: &[u8], init: RamInit) -> Self {
+    let cartridge = Cartridge::new_with_ram_init(cart_rom.into(), if save.len() > 0 {
+      Some(save.to_vec())
+    } else {
+      None
+    }, init).unwrap_or_else(|e| panic!("{}", e));
+    let is_cgb = cartridge.is_cgb;
+    Self::boot(Bootrom::new(), cartridge, is_cgb)
+  }
+
+  // Runs `bootrom`, a 0x900 byte CGB boot ROM, instead of the built-in one. The KEY0 value it writes
+  // before unmapping itself decides whether the cartridge runs in CGB or DMG compatibility mode.
+  pub fn new_with_bootrom(cart_rom: &[u8], save: &[u8], bootrom: &[u8]) -> Self {
+    assert!(bootrom.len() == 0x900, "CGB boot ROM must be 0x900 bytes");
+    let cartridge = Cartridge::new(cart_rom.into(), if !save.is_empty() {
+      Some(save.to_vec())
+    } else {
+      None
+    }).unwrap_or_else(|e| panic!("{}", e));
+    Self::boot(Bootrom::from_data(bootrom.to_vec()), cartridge, true)
+  }
+
+  fn boot(bootrom: Bootrom, cartridge: Cartridge, is_cgb: bool) -> Self {
+    let compat_palettes = cartridge.compat_palettes();
+    let mut peripherals = Peripherals::new(bootrom, cartridge, is_cgb);
+    if !is_cgb {
+      peripherals.ppu.set_compat_palettes(compat_palettes.bg, compat_palettes.obj0, compat_palettes.obj1);
+    }
     let cpu = Cpu::new();
     Self {
       cpu,
       peripherals,
+      recording: None,
+      playback: None,
+      taps: Vec::new(),
+      snapshots: None,
+    }
+  }
+
+  // Powers the machine off and on again, re-running the boot ROM. The cartridge keeps its ROM and SRAM.
+  pub fn hard_reset(&mut self) {
+    let mut cartridge = self.peripherals.cartridge.clone();
+    cartridge.reset();
+    let is_cgb = self.peripherals.ppu.is_cgb();
+    let bootrom = self.peripherals.bootrom.rebooted();
+    self.restore(Self::boot(bootrom, cartridge, is_cgb));
+  }
+
+  // Replaces the whole machine with `state`, e.g. a deserialized save state, keeping the callbacks and bus devices.
+  pub fn restore(&mut self, state: GameBoy) {
+    let callback = self.peripherals.apu.callback.take();
+    let ring = self.peripherals.apu.ring.take();
+    let hblank_callback = self.peripherals.ppu.hblank_callback.take();
+    let devices = std::mem::take(&mut self.peripherals.devices);
+    let read_watches = std::mem::take(&mut self.peripherals.read_watches);
+    // The history belongs to the old timeline.
+    let snapshots = self.snapshots.as_ref().map(|_| VecDeque::new());
+    *self = state;
+    self.peripherals.apu.callback = callback;
+    self.peripherals.apu.ring = ring;
+    self.peripherals.ppu.hblank_callback = hblank_callback;
+    self.peripherals.devices = devices;
+    self.peripherals.read_watches = read_watches;
+    self.snapshots = snapshots;
+  }
+
+  // Keeps a snapshot at every instruction so that `step_back` can undo them. This is slow and memory hungry.
+  pub fn set_instruction_snapshots(&mut self, on: bool) {
+    self.snapshots = if on { Some(VecDeque::new()) } else { None };
+  }
+
+  // Returns to the start of the last instruction that began executing. Returns false if there is no snapshot of it.
+  pub fn step_back(&mut self) -> bool {
+    let Some(mut snapshots) = self.snapshots.take() else { return false };
+    let prev = snapshots.pop_back();
+    let ret = prev.is_some();
+    if let Some(prev) = prev {
+      let recording = self.recording.take();
+      self.restore(*prev);
+      self.recording = recording;
+    }
+    self.snapshots = Some(snapshots);
+    ret
+  }
+
+  fn take_snapshot(&mut self) {
+    let Some(mut snapshots) = self.snapshots.take() else { return };
+    if snapshots.len() == MAX_INSTRUCTION_SNAPSHOTS {
+      snapshots.pop_front();
+    }
+    // The movie being recorded would otherwise be copied into every snapshot.
+    let recording = self.recording.take();
+    snapshots.push_back(Box::new(self.clone()));
+    self.recording = recording;
+    self.snapshots = Some(snapshots);
+  }
+
+  // Restarts the machine directly in the state the boot ROM leaves behind.
+  pub fn reset(&mut self) {
+    self.hard_reset();
+    self.init_post_boot();
+  }
+
+  // Skips the boot ROM, setting the CPU and IO registers to the values it leaves behind.
+  pub fn init_post_boot(&mut self) {
+    self.cpu.init_post_boot(self.peripherals.ppu.is_cgb());
+    self.peripherals.init_post_boot(&mut self.cpu.interrupts);
+  }
+
+  // Swaps in a new ROM without touching the rest of the machine. SRAM is kept if the size matches.
+  pub fn replace_rom(&mut self, rom: &[u8]) -> Result<(), String> {
+    Cartridge::validate(rom, &[])?;
+    let old = &self.peripherals.cartridge;
+    let mut cartridge = Cartridge::new(rom.into(), None).map_err(|e| e.to_string())?;
+    if cartridge.sram.len() == old.sram.len() {
+      cartridge.sram = old.sram.clone();
+    }
+    self.peripherals.cartridge = cartridge;
+    Ok(())
+  }
+
+  // Battery-backed RAM only, unlike a save state. The format is the raw SRAM in address order, the same as
+  // a .sav file, so it doesn't depend on endianness or the emulator version. There is no RTC trailer
+  // as the MBC3 clock isn't emulated.
+  pub fn export_save(&self) -> Vec<u8> {
+    self.peripherals.cartridge.sram_bytes().to_vec()
+  }
+  // Loads data from `export_save` into the running cartridge. Fails unless it is exactly the size of SRAM.
+  pub fn import_save(&mut self, data: &[u8]) -> Result<(), String> {
+    self.peripherals.cartridge.load_sram(data)
+  }
+
+  // Presses a button. Use this instead of `Joypad::button_down` so that movies can record it.
+  pub fn button_down(&mut self, button: Button) {
+    self.record_input(button, true);
+    self.peripherals.joypad.button_down(&mut self.cpu.interrupts, button);
+  }
+
+  pub fn button_up(&mut self, button: Button) {
+    self.record_input(button, false);
+    self.peripherals.joypad.button_up(button);
+  }
+
+  // Presses the button now and releases it after `frames` vblanks.
+  pub fn tap_button(&mut self, button: Button, frames: u32) {
+    self.button_down(button);
+    self.taps.push((button, frames));
+  }
+
+  fn release_taps(&mut self) {
+    for tap in self.taps.iter_mut() {
+      tap.1 = tap.1.saturating_sub(1);
+    }
+    let (expired, taps): (Vec<_>, Vec<_>) = std::mem::take(&mut self.taps).into_iter().partition(|&(_, frames)| frames == 0);
+    self.taps = taps;
+    for (button, _) in expired {
+      // Keep the button held while an overlapping tap still needs it.
+      if !self.taps.iter().any(|&(b, _)| b == button) {
+        self.button_up(button);
+      }
+    }
+  }
+
+  fn record_input(&mut self, button: Button, down: bool) {
+    if let Some(movie) = self.recording.as_mut() {
+      movie.inputs.push(MovieInput { cycle: movie.cycles, button, down });
     }
   }
 
+  // Starts recording inputs from the current state, discarding any previous recording.
+  pub fn start_recording(&mut self) {
+    self.recording = None;
+    self.playback = None;
+    self.recording = Some(Movie::new(self.clone()));
+  }
+
+  pub fn stop_recording(&mut self) -> Option<Movie> {
+    self.recording.take()
+  }
+
+  pub fn movie(&self) -> Option<Movie> {
+    self.recording.clone()
+  }
+
+  // Restores the state the movie was recorded from and replays its inputs at the recorded cycles.
+  pub fn play_movie(&mut self, movie: Movie) {
+    self.restore(*movie.state);
+    self.playback = Some(Playback::new(movie.inputs));
+  }
+
+  pub fn is_playing_movie(&self) -> bool {
+    self.playback.is_some()
+  }
+
+  // Mooneye test ROMs execute `LD B,B` when done, with the Fibonacci numbers in B-L on success and 0x42 on failure.
+  // Other `LD B,B`s are ignored.
+  pub fn check_mooneye_result(&mut self) -> Option<bool> {
+    if !self.cpu.take_breakpoint_hit() {
+      return None;
+    }
+    let regs = self.cpu.registers();
+    match [regs.b, regs.c, regs.d, regs.e, regs.h, regs.l] {
+      [3, 5, 8, 13, 21, 34] => Some(true),
+      [0x42, 0x42, 0x42, 0x42, 0x42, 0x42] => Some(false),
+      _ => None,
+    }
+  }
+
+  // A message for the user when the cartridge is unlikely to run in the emulated mode,
+  // e.g. a CGB-only cartridge forced into DMG mode, which shows a "please use Game Boy Color" screen.
+  pub fn compatibility_warning(&self) -> Option<String> {
+    let cartridge = &self.peripherals.cartridge;
+    if cartridge.cgb_flag == CgbFlag::CgbOnly && !self.peripherals.ppu.is_cgb() {
+      return Some(format!("{} only works on Game Boy Color, but is running in DMG mode.", cartridge.title));
+    }
+    None
+  }
+
+  // Bytes the game sent over the link port with the internal clock, e.g. the text of Blargg's test results.
+  pub fn take_serial_output(&mut self) -> Vec<u8> {
+    self.peripherals.serial.take_output()
+  }
+
+  /// A hash of the screen, for comparing runs against golden values without storing whole frames.
+  ///
+  /// ```
+  /// use gbemu::gameboy::GameBoy;
+  ///
+  /// // A blank ROM with a valid header checksum.
+  /// let mut rom = vec![0; 0x8000];
+  /// rom[0x14D] = rom[0x134..0x14D].iter().fold(0u8, |acc, &b| acc.wrapping_sub(b).wrapping_sub(1));
+  /// let mut gameboy = GameBoy::new(&rom, &[]);
+  /// gameboy.reset();
+  /// let mut other = gameboy.clone();
+  /// gameboy.run_cycles(100_000);
+  /// other.run_cycles(100_000);
+  /// assert_eq!(gameboy.frame_hash(), other.frame_hash());
+  /// ```
+  pub fn frame_hash(&self) -> u64 {
+    self.peripherals.ppu.frame_hash()
+  }
+
+  // Same as `Peripherals::peek`/`poke`, including IF and IE.
+  pub fn peek(&self, addr: u16) -> u8 {
+    match addr {
+      0xFF0F | 0xFFFF => self.cpu.interrupts.read(addr),
+      _               => self.peripherals.peek(addr),
+    }
+  }
+  pub fn poke(&mut self, addr: u16, val: u8) {
+    match addr {
+      0xFF0F | 0xFFFF => self.cpu.interrupts.write(addr, val),
+      _               => self.peripherals.poke(addr, val),
+    }
+  }
+
+  // Watches reads of `addr`, e.g. a flag the game polls. Check `take_read_watch_hit` after running.
+  pub fn set_read_watch(&mut self, addr: u16) {
+    self.peripherals.set_read_watch(addr);
+  }
+  pub fn clear_read_watches(&mut self) {
+    self.peripherals.clear_read_watches();
+  }
+  pub fn take_read_watch_hit(&mut self) -> Option<u16> {
+    self.peripherals.take_read_watch_hit()
+  }
+
+  // Disassembles the instruction at `addr` with the current bank mapping. Also returns its length.
+  pub fn disassemble(&self, addr: u16) -> (String, u16) {
+    disasm::disassemble(|a| self.peek(a), addr)
+  }
+  // Every instruction starting in `start..end`, e.g. to dump the mapped ROM.
+  pub fn disassemble_range(&self, start: u16, end: u16) -> Vec<(u16, String)> {
+    let mut ret = Vec::new();
+    let mut addr = start as u32;
+    while addr < end as u32 {
+      let (inst, len) = self.disassemble(addr as u16);
+      ret.push((addr as u16, inst));
+      addr += len as u32;
+    }
+    ret
+  }
+
+  // Number of `emulate_cycle` calls covering `dur`. This doesn't depend on the machine since
+  // CGB double speed mode can't be entered: KEY1 and STOP are not emulated.
+  pub fn cycles_for_duration(dur: Duration) -> u64 {
+    (dur.as_nanos() * CPU_CLOCK_HZ / 4 / 1_000_000_000) as u64
+  }
+
+  // Yields each completed frame as RGBA, e.g. `gameboy.frames().nth(599)` to run for 10 seconds.
+  pub fn frames(&mut self) -> Frames<'_> {
+    Frames { gameboy: self }
+  }
+
+  // Runs exactly `n` M-cycles regardless of frame boundaries.
+  pub fn run_cycles(&mut self, n: u64) {
+    for _ in 0..n {
+      self.emulate_cycle();
+    }
+  }
+
+  // Runs until the instruction at `addr` is about to execute. Returns false if that doesn't happen within `max_cycles`.
+  pub fn run_until_pc(&mut self, addr: u16, max_cycles: u64) -> bool {
+    for _ in 0..max_cycles {
+      self.emulate_cycle();
+      if self.cpu.at_instruction_boundary() && self.cpu.registers().pc == addr {
+        return true;
+      }
+    }
+    false
+  }
+
   pub fn emulate_cycle(&mut self) -> bool {
-    self.cpu.emulate_cycle(&mut self.peripherals);
+    if let Some(playback) = self.playback.as_mut() {
+      for input in playback.step() {
+        if input.down {
+          self.peripherals.joypad.button_down(&mut self.cpu.interrupts, input.button);
+        } else {
+          self.peripherals.joypad.button_up(input.button);
+        }
+      }
+      if playback.is_finished() {
+        self.playback = None;
+      }
+    }
+    if let Some(movie) = self.recording.as_mut() {
+      movie.cycles += 1;
+    }
+    // The CPU is stalled during general DMA.
+    if self.peripherals.ppu.general_dma.is_none() {
+      if self.snapshots.is_some() && self.cpu.at_instruction_boundary() {
+        self.take_snapshot();
+      }
+      self.cpu.emulate_cycle(&mut self.peripherals);
+    }
     self.peripherals.timer.emulate_cycle(&mut self.cpu.interrupts);
     self.peripherals.serial.emulate_cycle(&mut self.cpu.interrupts);
     self.peripherals.apu.emulate_cycle();
@@ -46,13 +448,459 @@ impl GameBoy {
       }
       self.peripherals.ppu.hblank_dma_emulate_cycle(src);
     }
-    if let Some(len) = self.peripherals.ppu.general_dma {
-      let mut src = Vec::new();
-      for addr in self.peripherals.ppu.hdma_src..self.peripherals.ppu.hdma_src + len {
-        src.push(self.peripherals.read(&self.cpu.interrupts, addr));
+    if self.peripherals.ppu.general_dma.is_some() {
+      let src = self.peripherals.ppu.hdma_src;
+      let vals = [
+        self.peripherals.read(&self.cpu.interrupts, src),
+        self.peripherals.read(&self.cpu.interrupts, src + 1),
+      ];
+      self.peripherals.ppu.general_dma_emulate_cycle(vals);
+    }
+    let vblank = self.peripherals.ppu.emulate_cycle(&mut self.cpu.interrupts);
+    if vblank && !self.taps.is_empty() {
+      self.release_taps();
+    }
+    vblank
+  }
+}
+
+pub struct Frames<'a> {
+  gameboy: &'a mut GameBoy,
+}
+
+impl Iterator for Frames<'_> {
+  type Item = Vec<u8>;
+  fn next(&mut self) -> Option<Vec<u8>> {
+    // There is no VBlank while the LCD is off, so a frame's worth of cycles is run at most.
+    for _ in 0..CYCLES_PER_FRAME {
+      if self.gameboy.emulate_cycle() {
+        break;
       }
-      self.peripherals.ppu.general_dma_emulate_cycle(src);
     }
-    self.peripherals.ppu.emulate_cycle(&mut self.cpu.interrupts)
+    Some(self.gameboy.peripherals.ppu.front_buffer().to_vec())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::cartridge::tests::{fix_checksum, rom};
+
+  // A ROM ONLY cartridge which jumps to `program` at 0x0150.
+  fn program_rom(program: &[u8]) -> Vec<u8> {
+    let mut rom = rom(0x00, 0, 0);
+    rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x0150..0x0150 + program.len()].copy_from_slice(program);
+    fix_checksum(&mut rom);
+    rom
+  }
+
+  fn rom_with_cgb_flag(flag: u8) -> Vec<u8> {
+    let mut rom = rom(0x00, 0, 0);
+    rom[0x143] = flag;
+    fix_checksum(&mut rom);
+    rom
+  }
+
+  #[test]
+  fn cgb_mode_follows_the_header() {
+    for (flag, is_cgb) in [(0x00, false), (0x80, true), (0xC0, true)] {
+      let gameboy = GameBoy::new(&rom_with_cgb_flag(flag), &[]);
+      assert_eq!(gameboy.peripherals.ppu.is_cgb(), is_cgb, "flag {:02x}", flag);
+    }
+  }
+
+  #[test]
+  fn force_dmg() {
+    for flag in [0x00, 0x80, 0xC0] {
+      let gameboy = GameBoy::new_forced(&rom_with_cgb_flag(flag), &[], CgbMode::ForceDmg);
+      assert!(!gameboy.peripherals.ppu.is_cgb(), "flag {:02x}", flag);
+    }
+  }
+  #[test]
+  fn compatibility_warning_for_cgb_only_in_dmg_mode() {
+    let cgb_only = GameBoy::new_forced(&rom_with_cgb_flag(0xC0), &[], CgbMode::ForceDmg);
+    assert!(cgb_only.compatibility_warning().is_some());
+    assert_eq!(GameBoy::new(&rom_with_cgb_flag(0xC0), &[]).compatibility_warning(), None);
+    let dmg = GameBoy::new_forced(&rom_with_cgb_flag(0x00), &[], CgbMode::ForceDmg);
+    assert_eq!(dmg.compatibility_warning(), None);
+  }
+  #[test]
+  fn reset_keeps_sram() {
+    let mut gameboy = GameBoy::new(&rom(0x03, 0, 0x02), &[]);
+    gameboy.reset();
+    gameboy.run_cycles(1000);
+    gameboy.peripherals.write(&mut gameboy.cpu.interrupts, 0x0000, 0x0A);
+    gameboy.peripherals.write(&mut gameboy.cpu.interrupts, 0xA000, 0x5A);
+    gameboy.peripherals.write(&mut gameboy.cpu.interrupts, 0xC000, 0x5A);
+    gameboy.reset();
+    assert_eq!(gameboy.cpu.registers().sp, 0xFFFE);
+    // The entry point is the first instruction fetched.
+    assert!(gameboy.run_until_pc(0x0100, 1));
+    assert_eq!(gameboy.peripherals.wram()[0], 0x00);
+    assert_eq!(gameboy.export_save()[0], 0x5A);
+    // The MBC is back to its power-on state, with SRAM disabled.
+    assert_eq!(gameboy.peripherals.read(&gameboy.cpu.interrupts, 0xA000), 0xFF);
+  }
+  #[test]
+  fn seeded_init_pattern_is_reproducible() {
+    let rom = rom(0x00, 0, 0);
+    let a = GameBoy::new_with_init_pattern(&rom, &[], InitPattern::Seeded(42));
+    let b = GameBoy::new_with_init_pattern(&rom, &[], InitPattern::Seeded(42));
+    let c = GameBoy::new_with_init_pattern(&rom, &[], InitPattern::Seeded(43));
+    assert_eq!(a.peripherals.wram(), b.peripherals.wram());
+    assert_eq!(a.peripherals.hram(), b.peripherals.hram());
+    assert_ne!(a.peripherals.wram(), c.peripherals.wram());
+    assert!(a.peripherals.wram().iter().any(|&b| b != 0));
+    let ones = GameBoy::new_with_init_pattern(&rom, &[], InitPattern::Ones);
+    assert!(ones.peripherals.wram().iter().all(|&b| b == 0xFF));
+    assert!(GameBoy::new(&rom, &[]).peripherals.wram().iter().all(|&b| b == 0));
+  }
+  #[test]
+  fn movie_round_trip() {
+    // Selects the directions and copies P1 to BGP forever, so that the screen shows whether Right is pressed.
+    let mut gameboy = GameBoy::new(&program_rom(&[
+      0x3E, 0x20,       // LD A,$20
+      0xE0, 0x00,       // LDH ($00),A
+      0xF0, 0x00,       // LDH A,($00)
+      0xE0, 0x47,       // LDH ($47),A
+      0x18, 0xFA,       // JR -6
+    ]), &[]);
+    gameboy.reset();
+    gameboy.run_cycles(CYCLES_PER_FRAME as u64);
+    gameboy.start_recording();
+    gameboy.run_cycles(1000);
+    gameboy.button_down(Button::Right);
+    gameboy.run_cycles(CYCLES_PER_FRAME as u64);
+    gameboy.button_up(Button::Right);
+    gameboy.run_cycles(1000);
+    gameboy.button_down(Button::Right);
+    gameboy.run_cycles(CYCLES_PER_FRAME as u64 * 2);
+    let movie = gameboy.stop_recording().unwrap();
+    let hash = gameboy.frame_hash();
+
+    let mut replay = GameBoy::new(&program_rom(&[]), &[]);
+    replay.play_movie(movie.clone());
+    replay.run_cycles(movie.cycles);
+    assert_eq!(replay.frame_hash(), hash);
+
+    // Without the inputs, the last frame differs.
+    let mut idle = GameBoy::new(&program_rom(&[]), &[]);
+    idle.restore(*movie.state);
+    idle.run_cycles(movie.cycles);
+    assert_ne!(idle.frame_hash(), hash);
+  }
+  #[test]
+  fn snapshots_leave_out_the_recording() {
+    let mut gameboy = GameBoy::new(&program_rom(&[]), &[]);
+    gameboy.reset();
+    gameboy.start_recording();
+    gameboy.set_instruction_snapshots(true);
+    gameboy.run_cycles(100);
+    let snapshots = gameboy.snapshots.as_ref().unwrap();
+    assert!(!snapshots.is_empty());
+    assert!(snapshots.iter().all(|snapshot| snapshot.recording.is_none()));
+    assert!(gameboy.step_back());
+    assert!(gameboy.recording.is_some());
+  }
+  #[test]
+  fn mooneye_result_from_ld_b_b() {
+    // Loads B-L, executes `LD B,B` once and loops.
+    let result = |regs: [u8; 6]| {
+      let mut program = Vec::new();
+      for (i, val) in regs.into_iter().enumerate() {
+        program.extend([0x06 + ((i as u8) << 3), val]);   // LD r,val
+      }
+      program.extend([0x40, 0x18, 0xFE]);                  // LD B,B; JR -2
+      let mut gameboy = GameBoy::new(&program_rom(&program), &[]);
+      gameboy.reset();
+      gameboy.run_cycles(1000);
+      let result = gameboy.check_mooneye_result();
+      // The breakpoint is cleared once read.
+      assert_eq!(gameboy.check_mooneye_result(), None);
+      result
+    };
+    assert_eq!(result([3, 5, 8, 13, 21, 34]), Some(true));
+    assert_eq!(result([0x42; 6]), Some(false));
+    assert_eq!(result([1, 2, 3, 4, 5, 6]), None);
+  }
+  #[test]
+  fn general_dma_stalls_the_cpu_for_2_bytes_per_cycle() {
+    let mut rom = program_rom(&[
+      0x3E, 0xC0,       // LD A,$C0
+      0xE0, 0x51,       // LDH ($51),A
+      0xAF,             // XOR A
+      0xE0, 0x52,       // LDH ($52),A
+      0xE0, 0x53,       // LDH ($53),A
+      0xE0, 0x54,       // LDH ($54),A
+      0x3E, 0x7F,       // LD A,$7F
+      0xE0, 0x55,       // LDH ($55),A
+      0x18, 0xFE,       // JR -2
+    ]);
+    rom[0x143] = 0x80;
+    fix_checksum(&mut rom);
+    let mut gameboy = GameBoy::new(&rom, &[]);
+    gameboy.reset();
+    for i in 0..0x800 {
+      gameboy.poke(0xC000 + i, i as u8 ^ (i >> 8) as u8);
+    }
+    while gameboy.peripherals.ppu.general_dma.is_none() {
+      gameboy.emulate_cycle();
+    }
+    // The write to HDMA5 already copied the first 2 bytes.
+    let pc = gameboy.cpu.registers().pc;
+    let mut cycles = 1;
+    while gameboy.peripherals.ppu.general_dma.is_some() {
+      gameboy.emulate_cycle();
+      assert_eq!(gameboy.cpu.registers().pc, pc);
+      cycles += 1;
+    }
+    assert_eq!(cycles, 0x800 / 2);
+    for i in 0..0x800 {
+      assert_eq!(gameboy.peek(0x8000 + i), i as u8 ^ (i >> 8) as u8, "{:04x}", 0x8000 + i);
+    }
+  }
+  #[test]
+  fn run_until_pc_stops_at_each_pass() {
+    let mut gameboy = GameBoy::new(&program_rom(&[
+      0x3C,             // INC A
+      0x18, 0xFD,       // JR -3
+    ]), &[]);
+    gameboy.reset();
+    assert!(gameboy.run_until_pc(0x0151, 100));
+    let a = gameboy.cpu.registers().a;
+    // Each call goes around the loop once more.
+    for i in 1..=3 {
+      assert!(gameboy.run_until_pc(0x0151, 100));
+      assert_eq!(gameboy.cpu.registers().a, a.wrapping_add(i));
+    }
+    assert!(!gameboy.run_until_pc(0x0200, 1000));
+  }
+  #[test]
+  fn read_watch_flags_cpu_reads_of_its_address() {
+    let mut gameboy = GameBoy::new(&program_rom(&[
+      0xFA, 0x01, 0xC0, // LD A,($C001)
+      0xFA, 0x00, 0xC0, // LD A,($C000)
+      0x18, 0xFE,       // JR -2
+    ]), &[]);
+    gameboy.reset();
+    gameboy.set_read_watch(0xC000);
+    assert!(gameboy.run_until_pc(0x0153, 100));
+    assert_eq!(gameboy.take_read_watch_hit(), None);
+    gameboy.peek(0xC000);
+    assert_eq!(gameboy.take_read_watch_hit(), None);
+    assert!(gameboy.run_until_pc(0x0156, 100));
+    assert_eq!(gameboy.take_read_watch_hit(), Some(0xC000));
+    assert_eq!(gameboy.take_read_watch_hit(), None);
+  }
+  #[test]
+  fn try_new_rejects_what_new_panics_on() {
+    assert!(GameBoy::try_new(&rom(0x03, 0, 0x02), &[0; 0x2000]).is_ok());
+    assert!(GameBoy::try_new(&rom(0x03, 0, 0x02), &[0; 0x800]).is_err());
+    assert!(GameBoy::try_new(&rom(0xFF, 0, 0), &[]).is_err());
+    let mut bad_checksum = rom(0x00, 0, 0);
+    bad_checksum[0x14D] ^= 1;
+    assert!(GameBoy::try_new(&bad_checksum, &[]).is_err());
+    assert!(GameBoy::try_new(&rom(0x00, 0, 0)[..0x4000], &[]).is_err());
+  }
+  #[test]
+  fn save_export_import_round_trip() {
+    let mut gameboy = GameBoy::new(&rom(0x03, 0, 0x02), &[]);
+    let save: Vec<u8> = (0..0x2000).map(|i| (i * 7) as u8).collect();
+    gameboy.import_save(&save).unwrap();
+    assert_eq!(gameboy.export_save(), save);
+    let loaded = GameBoy::new(&rom(0x03, 0, 0x02), &gameboy.export_save());
+    assert_eq!(loaded.export_save(), save);
+    assert!(gameboy.import_save(&save[..0x800]).is_err());
+    assert_eq!(gameboy.export_save(), save);
+  }
+  #[test]
+  fn peek_and_poke_skip_side_effects() {
+    let mut gameboy = GameBoy::new(&rom(0x03, 0, 0x02), &[]);
+    gameboy.reset();
+    while gameboy.peripherals.ppu.mode() != crate::PpuMode::Drawing {
+      gameboy.emulate_cycle();
+    }
+    // VRAM and OAM are locked for the CPU during Mode 3.
+    gameboy.poke(0x8000, 0x12);
+    gameboy.poke(0xFE00, 0x34);
+    assert_eq!((gameboy.peek(0x8000), gameboy.peek(0xFE00)), (0x12, 0x34));
+    let interrupts = &gameboy.cpu.interrupts;
+    assert_eq!(gameboy.peripherals.read(interrupts, 0x8000), 0xFF);
+    assert_eq!(gameboy.peripherals.read(interrupts, 0xFE00), 0xFF);
+
+    gameboy.set_read_watch(0xC000);
+    gameboy.peek(0xC000);
+    assert_eq!(gameboy.take_read_watch_hit(), None);
+
+    // ROM and disabled SRAM are patched directly.
+    gameboy.poke(0x0150, 0xAB);
+    assert_eq!(gameboy.peek(0x0150), 0xAB);
+    gameboy.poke(0xA000, 0x55);
+    assert_eq!(gameboy.peek(0xA000), 0x55);
+    assert_eq!(gameboy.export_save()[0], 0x55);
+  }
+  #[test]
+  fn replace_rom_keeps_the_machine_state() {
+    let titled_rom = |title: &[u8], sram_size: u8| {
+      let mut rom = rom(0x03, 0, sram_size);
+      rom[0x134..0x134 + title.len()].copy_from_slice(title);
+      rom[0x0150] = title[0];
+      fix_checksum(&mut rom);
+      rom
+    };
+    let mut gameboy = GameBoy::new(&titled_rom(b"FIRST", 0x02), &[]);
+    gameboy.reset();
+    gameboy.run_cycles(1000);
+    gameboy.poke(0xA000, 0x55);
+    let pc = gameboy.cpu.registers().pc;
+
+    gameboy.replace_rom(&titled_rom(b"SECOND", 0x02)).unwrap();
+    assert_eq!(gameboy.peripherals.cartridge.title, "SECOND");
+    assert_eq!(gameboy.peek(0x0150), b'S');
+    assert_eq!(gameboy.peek(0xA000), 0x55);
+    assert_eq!(gameboy.cpu.registers().pc, pc);
+
+    // Back to the first ROM, with a different SRAM size this time.
+    gameboy.replace_rom(&titled_rom(b"FIRST", 0x03)).unwrap();
+    assert_eq!(gameboy.peripherals.cartridge.title, "FIRST");
+    assert_eq!(gameboy.peek(0x0150), b'F');
+    assert_eq!(gameboy.peek(0xA000), 0x00);
+
+    let mut bad = titled_rom(b"THIRD", 0x03);
+    bad[0x14D] ^= 0xFF;
+    assert!(gameboy.replace_rom(&bad).is_err());
+    assert_eq!(gameboy.peripherals.cartridge.title, "FIRST");
+  }
+  #[test]
+  fn machines_from_one_cartridge_have_their_own_sram() {
+    let cartridge = Cartridge::new(rom(0x03, 0, 0x02), None).unwrap();
+    let mut a = GameBoy::from_cartridge(cartridge.clone());
+    let mut b = GameBoy::from_cartridge(cartridge);
+    a.reset();
+    b.reset();
+    a.poke(0xA000, 0x11);
+    b.poke(0xA001, 0x22);
+    assert_eq!((a.peek(0xA000), a.peek(0xA001)), (0x11, 0x00));
+    assert_eq!((b.peek(0xA000), b.peek(0xA001)), (0x00, 0x22));
+  }
+  #[test]
+  fn step_back_undoes_instructions() {
+    let mut gameboy = GameBoy::new(&program_rom(&[
+      0x3C,             // INC A
+      0x04,             // INC B
+      0x0C,             // INC C
+    ]), &[]);
+    gameboy.reset();
+    assert!(gameboy.run_until_pc(0x0150, 100));
+    gameboy.set_instruction_snapshots(true);
+    let regs = |gameboy: &GameBoy| {
+      let regs = gameboy.cpu.registers();
+      (regs.pc, regs.a, regs.b, regs.c, regs.f)
+    };
+    let mut states = vec![regs(&gameboy)];
+    for _ in 0..3 {
+      gameboy.emulate_cycle();
+      while !gameboy.cpu.at_instruction_boundary() {
+        gameboy.emulate_cycle();
+      }
+      states.push(regs(&gameboy));
+    }
+    assert_eq!(states[3].0, 0x0153);
+    assert!(gameboy.step_back());
+    assert_eq!(regs(&gameboy), states[2]);
+    assert!(gameboy.step_back());
+    assert_eq!(regs(&gameboy), states[1]);
+    assert!(gameboy.step_back());
+    assert_eq!(regs(&gameboy), states[0]);
+    assert!(!gameboy.step_back());
+  }
+  #[test]
+  fn tap_button_releases_after_its_frames() {
+    let mut gameboy = GameBoy::new(&program_rom(&[]), &[]);
+    gameboy.reset();
+    let next_frame = |gameboy: &mut GameBoy| while !gameboy.emulate_cycle() {};
+    next_frame(&mut gameboy);
+    let held = |gameboy: &GameBoy| gameboy.peripherals.joypad.input_byte();
+    gameboy.tap_button(Button::A, 2);
+    // B overlaps with a longer tap of itself.
+    gameboy.tap_button(Button::B, 1);
+    gameboy.tap_button(Button::B, 3);
+    assert_eq!(held(&gameboy), 0b11);
+    next_frame(&mut gameboy);
+    assert_eq!(held(&gameboy), 0b11);
+    next_frame(&mut gameboy);
+    assert_eq!(held(&gameboy), 0b10);
+    next_frame(&mut gameboy);
+    assert_eq!(held(&gameboy), 0b00);
+  }
+  #[test]
+  fn frames_yields_one_frame_per_item() {
+    let mut gameboy = GameBoy::new(&program_rom(&[0x18, 0xFE]), &[]); // JR -2
+    gameboy.reset();
+    let mut stepped = gameboy.clone();
+    let frame = gameboy.frames().nth(2).unwrap();
+    assert_eq!(frame.len(), crate::LCD_PIXELS * 4);
+    for _ in 0..3 {
+      while !stepped.emulate_cycle() {}
+    }
+    assert_eq!(frame, stepped.peripherals.ppu.front_buffer());
+    assert_eq!((gameboy.peek(0xFF04), gameboy.peek(0xFF44)), (stepped.peek(0xFF04), stepped.peek(0xFF44)));
+
+    // With the LCD off there is no VBlank, but a frame's worth of cycles still yields one.
+    gameboy.poke(0xFF40, 0x00);
+    let mut stepped = gameboy.clone();
+    stepped.run_cycles(CYCLES_PER_FRAME as u64);
+    assert_eq!(gameboy.frames().next().unwrap().len(), crate::LCD_PIXELS * 4);
+    assert_eq!(gameboy.peek(0xFF04), stepped.peek(0xFF04));
+  }
+  #[test]
+  fn post_boot_registers() {
+    for (cgb_flag, af, bc, de, hl) in [(0x00, 0x01B0, 0x0013, 0x00D8, 0x014D), (0x80, 0x1180, 0x0000, 0xFF56, 0x000D)] {
+      let mut gameboy = GameBoy::new(&rom_with_cgb_flag(cgb_flag), &[]);
+      gameboy.reset();
+      // Up to the first fetch, so that PC points to the entry point.
+      gameboy.emulate_cycle();
+      let regs = gameboy.cpu.registers();
+      assert_eq!((regs.af(), regs.bc(), regs.de(), regs.hl()), (af, bc, de, hl), "CGB flag {:02x}", cgb_flag);
+      assert_eq!((regs.sp, regs.pc), (0xFFFE, 0x0100));
+      assert_eq!((gameboy.peek(0xFF40), gameboy.peek(0xFF47), gameboy.peek(0xFF50)), (0x91, 0xFC, 0xFF));
+      if cgb_flag == 0x00 {
+        // The internal counter is 0xABCC, of which DIV is the upper byte.
+        assert_eq!(gameboy.peek(0xFF04), 0xAB);
+      }
+    }
+  }
+  #[test]
+  fn dmg_cartridge_gets_compat_palettes_from_the_cgb_boot_rom() {
+    let mut tetris = rom(0x00, 0, 0);
+    tetris[0x134..0x13A].copy_from_slice(b"TETRIS");
+    tetris[0x14B] = 0x01;
+    fix_checksum(&mut tetris);
+    // Selects DMG compatibility mode through KEY0 and unmaps itself, as the real one does for DMG cartridges.
+    let mut bootrom = vec![0; 0x900];
+    bootrom[..8].copy_from_slice(&[
+      0x3E, 0x04,       // LD A,$04
+      0xE0, 0x4C,       // LDH ($4C),A
+      0x3E, 0x01,       // LD A,$01
+      0xE0, 0x50,       // LDH ($50),A
+    ]);
+    let mut gameboy = GameBoy::new_with_bootrom(&tetris, &[], &bootrom);
+    assert!(gameboy.peripherals.ppu.is_cgb());
+    assert!(gameboy.run_until_pc(0x0100, 1000));
+    assert!(!gameboy.peripherals.ppu.is_cgb());
+
+    let palettes = gameboy.peripherals.cartridge.compat_palettes();
+    // Not the default of an unknown title.
+    let unknown = Cartridge::new(rom(0x00, 0, 0), None).unwrap().compat_palettes();
+    assert_ne!(palettes.bg, unknown.bg);
+    let mut bg = [0; 4];
+    for (i, color) in bg.iter_mut().enumerate() {
+      gameboy.poke(0xFF68, i as u8 * 2);
+      let low = gameboy.peek(0xFF69);
+      gameboy.poke(0xFF68, i as u8 * 2 + 1);
+      *color = u16::from_le_bytes([low, gameboy.peek(0xFF69)]);
+    }
+    assert_eq!(bg, palettes.bg);
   }
 }
@@ -7,6 +7,17 @@ impl HRam {
   pub fn new() -> Self {
     Self(vec![0; 0x80])
   }
+  pub fn fill(&mut self, next: &mut impl FnMut() -> u8) {
+    self.0.iter_mut().for_each(|b| *b = next());
+  }
+  // FF80-FFFE in address order.
+  pub fn as_slice(&self) -> &[u8] {
+    &self.0[..0x7f]
+  }
+  #[cfg(feature = "debug")]
+  pub fn as_mut_slice(&mut self) -> &mut [u8] {
+    &mut self.0[..0x7f]
+  }
   pub fn read(&self, addr: u16) -> u8 {
     self.0[(addr as usize) & 0x7f]
   }
@@ -35,11 +35,58 @@ impl Button {
   }
 }
 
+// What the game sees while opposing directions are held together, e.g. Left and Right on a keyboard.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SocdMode {
+  // Both are pressed, as on hardware. Some games misbehave.
+  #[default]
+  Passthrough,
+  // Neither is pressed.
+  Neutral,
+  // Only the one pressed last is.
+  LastWins,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "SavedJoypad")]
 pub struct Joypad {
   mode: u8,
   action: u8,
   direction: u8,
+  socd_mode: SocdMode,
+  // Directions physically held and the last one pressed on each axis, 1 for pressed, which `direction` is resolved from.
+  held_direction: u8,
+  last_horizontal: u8,
+  last_vertical: u8,
+}
+
+// States saved before SOCD handling only have `direction`, which is then what is held.
+#[derive(Deserialize)]
+struct SavedJoypad {
+  mode: u8,
+  action: u8,
+  direction: u8,
+  #[serde(default)]
+  socd_mode: SocdMode,
+  held_direction: Option<u8>,
+  #[serde(default)]
+  last_horizontal: u8,
+  #[serde(default)]
+  last_vertical: u8,
+}
+
+impl From<SavedJoypad> for Joypad {
+  fn from(saved: SavedJoypad) -> Self {
+    Self {
+      mode: saved.mode,
+      action: saved.action,
+      direction: saved.direction,
+      socd_mode: saved.socd_mode,
+      held_direction: saved.held_direction.unwrap_or(!saved.direction & 0x0F),
+      last_horizontal: saved.last_horizontal,
+      last_vertical: saved.last_vertical,
+    }
+  }
 }
 
 impl Joypad {
@@ -48,8 +95,38 @@ impl Joypad {
       mode: 0,
       action: 0xFF,
       direction: 0xFF,
+      socd_mode: SocdMode::Passthrough,
+      held_direction: 0,
+      last_horizontal: 0,
+      last_vertical: 0,
     }
   }
+  pub fn set_socd_mode(&mut self, mode: SocdMode) {
+    self.socd_mode = mode;
+    self.direction = self.resolve_direction();
+  }
+  fn set_last_direction(&mut self, direction: u8) {
+    if direction & 0b0011 > 0 {
+      self.last_horizontal = direction;
+    } else {
+      self.last_vertical = direction;
+    }
+  }
+  fn resolve_direction(&self) -> u8 {
+    let mut pressed = self.held_direction;
+    for (pair, last) in [(0b0011, self.last_horizontal), (0b1100, self.last_vertical)] {
+      if pressed & pair == pair {
+        match self.socd_mode {
+          SocdMode::Passthrough => {},
+          SocdMode::Neutral     => pressed &= !pair,
+          SocdMode::LastWins    => pressed &= !pair | last,
+        }
+      }
+    }
+    !pressed
+  }
+  // With both groups selected, a line reads low if a button of either group is pressed.
+  // With neither selected, the low nibble reads 0xF.
   pub fn read(&self) -> u8 {
     let mut ret = 0xCF | self.mode;
     if ret & 0x10 == 0 {
@@ -64,12 +141,157 @@ impl Joypad {
     self.mode = 0x30 & val;
   }
   pub fn button_down(&mut self, interrupts: &mut Interrupts, button: Button) {
-    self.direction &= !button.as_direction();
+    let prev = self.read();
+    let direction = button.as_direction();
+    if direction > 0 {
+      self.held_direction |= direction;
+      self.set_last_direction(direction);
+      self.direction = self.resolve_direction();
+    }
     self.action &= !button.as_action();
-    interrupts.irq(interrupts::JOYPAD);
+    // The interrupt is requested when a selected input line goes from high to low.
+    if prev & !self.read() & 0x0F > 0 {
+      interrupts.irq(interrupts::JOYPAD);
+    }
   }
+  // Releasing a direction can reveal the opposite one which is still held, without an interrupt.
   pub fn button_up(&mut self, button: Button) {
-    self.direction |= button.as_direction();
+    self.held_direction &= !button.as_direction();
+    self.direction = self.resolve_direction();
     self.action |= button.as_action();
   }
+  // All buttons held as one byte, 1 for pressed: A, B, Select, Start in bits 0-3, then Right, Left, Up, Down in bits 4-7.
+  // Opposing directions are both included, and resolved by the SOCD mode of the joypad the byte is applied to.
+  pub fn input_byte(&self) -> u8 {
+    !self.action & 0x0F | self.held_direction << 4
+  }
+  pub fn apply_input_byte(&mut self, interrupts: &mut Interrupts, byte: u8) {
+    let prev = self.read();
+    self.action = 0xF0 | !byte & 0x0F;
+    // Newly pressed directions count as the last ones pressed, as with `button_down`.
+    for direction in [0b0001, 0b0010, 0b0100, 0b1000] {
+      if byte >> 4 & !self.held_direction & direction > 0 {
+        self.set_last_direction(direction);
+      }
+    }
+    self.held_direction = byte >> 4;
+    self.direction = self.resolve_direction();
+    if prev & !self.read() & 0x0F > 0 {
+      interrupts.irq(interrupts::JOYPAD);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Whether pressing `button` with `select` written to P1 requests the joypad interrupt.
+  fn interrupts_on_press(select: u8, button: Button) -> bool {
+    let mut joypad = Joypad::new();
+    let mut interrupts = Interrupts::default();
+    joypad.write(0xFF00, select);
+    joypad.button_down(&mut interrupts, button);
+    interrupts.intr_flags & interrupts::JOYPAD > 0
+  }
+
+  #[test]
+  fn interrupt_on_selected_lines_only() {
+    for (select, direction, action) in [(0x00, true, true), (0x10, false, true), (0x20, true, false), (0x30, false, false)] {
+      assert_eq!(interrupts_on_press(select, Button::Right), direction, "select {:02x}", select);
+      assert_eq!(interrupts_on_press(select, Button::A), action, "select {:02x}", select);
+    }
+  }
+
+  #[test]
+  fn no_interrupt_while_the_line_is_already_low() {
+    let mut joypad = Joypad::new();
+    let mut interrupts = Interrupts::default();
+    joypad.write(0xFF00, 0x00);
+    joypad.button_down(&mut interrupts, Button::Right);
+    interrupts.intr_flags = 0;
+    // A shares P10 with Right.
+    joypad.button_down(&mut interrupts, Button::A);
+    assert_eq!(interrupts.intr_flags & interrupts::JOYPAD, 0);
+  }
+  #[test]
+  fn read_each_select_combination() {
+    let mut joypad = Joypad::new();
+    let mut interrupts = Interrupts::default();
+    joypad.button_down(&mut interrupts, Button::Right);
+    joypad.button_down(&mut interrupts, Button::B);
+    for (select, expected) in [(0x00, 0xCC), (0x10, 0xDD), (0x20, 0xEE), (0x30, 0xFF)] {
+      joypad.write(0xFF00, select);
+      assert_eq!(joypad.read(), expected, "select {:02x}", select);
+    }
+  }
+
+  #[test]
+  fn old_states_keep_the_held_directions() {
+    let mut joypad: Joypad = serde_json::from_str(r#"{"mode":16,"action":255,"direction":250}"#).unwrap();
+    assert_eq!(joypad.held_direction, 0b0101);
+    joypad.button_up(Button::Right);
+    assert_eq!(joypad.direction & 0x0F, 0b1011);
+    // States with the field keep it as is.
+    let loaded: Joypad = serde_json::from_str(&serde_json::to_string(&joypad).unwrap()).unwrap();
+    assert_eq!(loaded.held_direction, 0b0100);
+  }
+  // The directions the game reads after pressing `buttons` in order.
+  fn directions_after(socd_mode: SocdMode, buttons: &[Button]) -> u8 {
+    let mut joypad = Joypad::new();
+    let mut interrupts = Interrupts::default();
+    joypad.set_socd_mode(socd_mode);
+    joypad.write(0xFF00, 0x20);
+    for &button in buttons {
+      joypad.button_down(&mut interrupts, button);
+    }
+    !joypad.read() & 0x0F
+  }
+
+  #[test]
+  fn opposing_directions_by_socd_mode() {
+    use Button::{Left, Right, Up};
+    let (right, left, up) = (0b0001, 0b0010, 0b0100);
+    for (buttons, passthrough, neutral, last_wins) in [
+      (&[Left, Right][..], left | right, 0, right),
+      (&[Right, Left], left | right, 0, left),
+      // Up is on the other axis, so it doesn't count as the last horizontal press.
+      (&[Left, Right, Up], left | right | up, up, right | up),
+      (&[Left, Up, Right], left | right | up, up, right | up),
+    ] {
+      assert_eq!(directions_after(SocdMode::Passthrough, buttons), passthrough, "{:?}", buttons);
+      assert_eq!(directions_after(SocdMode::Neutral, buttons), neutral, "{:?}", buttons);
+      assert_eq!(directions_after(SocdMode::LastWins, buttons), last_wins, "{:?}", buttons);
+    }
+    // Releasing the winner reveals the other one.
+    let mut joypad = Joypad::new();
+    let mut interrupts = Interrupts::default();
+    joypad.set_socd_mode(SocdMode::LastWins);
+    joypad.write(0xFF00, 0x20);
+    joypad.button_down(&mut interrupts, Button::Left);
+    joypad.button_down(&mut interrupts, Button::Right);
+    joypad.button_up(Button::Right);
+    assert_eq!(!joypad.read() & 0x0F, left);
+  }
+  #[test]
+  fn input_byte_round_trip() {
+    let mut interrupts = Interrupts::default();
+    for byte in 0..=0xFF {
+      let mut joypad = Joypad::new();
+      joypad.apply_input_byte(&mut interrupts, byte);
+      assert_eq!(joypad.input_byte(), byte);
+    }
+    // Replaying the bytes of a LastWins session resolves opposing directions the same way.
+    let mut live = Joypad::new();
+    let mut replay = Joypad::new();
+    for joypad in [&mut live, &mut replay] {
+      joypad.set_socd_mode(SocdMode::LastWins);
+      joypad.write(0xFF00, 0x20);
+    }
+    for button in [Button::Right, Button::Left, Button::Up, Button::Down] {
+      live.button_down(&mut interrupts, button);
+      replay.apply_input_byte(&mut interrupts, live.input_byte());
+      assert_eq!(replay.read(), live.read(), "{:?}", button);
+    }
+  }
 }
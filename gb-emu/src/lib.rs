@@ -6,16 +6,29 @@ pub const SAMPLE_RATE: u128 = 48000;
 pub const LCD_WIDTH: usize = 160;
 pub const LCD_HEIGHT: usize = 144;
 pub const LCD_PIXELS: usize = LCD_WIDTH * LCD_HEIGHT;
+pub const ASPECT_RATIO: f64 = LCD_WIDTH as f64 / LCD_HEIGHT as f64;
 
 pub mod gameboy;
 pub mod joypad;
+pub mod movie;
+pub mod peripherals;
+pub mod printer;
 mod apu;
 mod bootrom;
 mod cartridge;
 mod cpu;
-mod peripherals;
+mod disasm;
 mod ppu;
 mod serial;
 mod timer;
+mod undocumented;
 mod hram;
-mod wram;
\ No newline at end of file
+mod wram;
+#[cfg(test)]
+mod test_bus;
+
+pub use apu::{ApuDebug, ChannelDebug};
+pub use cartridge::{BankInfo, Cartridge, RamInit, UnsupportedMbc};
+pub use cpu::{Cpu, Registers};
+pub use ppu::{Layer, PpuMode};
+pub use serial::SerialDevice;
\ No newline at end of file
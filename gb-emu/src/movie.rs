@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  gameboy::GameBoy,
+  joypad::Button,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MovieInput {
+  pub cycle: u64,
+  pub button: Button,
+  pub down: bool,
+}
+
+// Inputs recorded from a save state, counted in M-cycles since the recording started.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Movie {
+  pub(crate) state: Box<GameBoy>,
+  pub inputs: Vec<MovieInput>,
+  pub(crate) cycles: u64,
+}
+
+impl Movie {
+  pub(crate) fn new(state: GameBoy) -> Self {
+    Self {
+      state: Box::new(state),
+      inputs: vec![],
+      cycles: 0,
+    }
+  }
+}
+
+#[derive(Clone)]
+pub(crate) struct Playback {
+  pub inputs: Vec<MovieInput>,
+  pub next: usize,
+  pub cycles: u64,
+}
+
+impl Playback {
+  pub fn new(inputs: Vec<MovieInput>) -> Self {
+    Self {
+      inputs,
+      next: 0,
+      cycles: 0,
+    }
+  }
+  // Returns the inputs scheduled for the current cycle and advances to the next one.
+  pub fn step(&mut self) -> &[MovieInput] {
+    let start = self.next;
+    while self.next < self.inputs.len() && self.inputs[self.next].cycle == self.cycles {
+      self.next += 1;
+    }
+    self.cycles += 1;
+    &self.inputs[start..self.next]
+  }
+  pub fn is_finished(&self) -> bool {
+    self.next >= self.inputs.len()
+  }
+}
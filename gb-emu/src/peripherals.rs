@@ -1,3 +1,5 @@
+use std::cell::Cell;
+
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -11,11 +13,36 @@ use crate::{
   timer::Timer,
   joypad::Joypad,
   serial::Serial,
+  undocumented::Undocumented,
 };
 
+// A device which can claim addresses before the default memory map. Devices are cloned with the machine,
+// so that snapshots, rewind and movies don't share them with the running machine.
+pub trait BusDevice: BusDeviceClone {
+  fn read(&self, addr: u16) -> Option<u8>;
+  fn write(&mut self, addr: u16, val: u8) -> bool;
+}
+
+// Implemented for every `Clone` device, which makes `Box<dyn BusDevice>` cloneable.
+pub trait BusDeviceClone {
+  fn clone_box(&self) -> Box<dyn BusDevice>;
+}
+
+impl<T: BusDevice + Clone + 'static> BusDeviceClone for T {
+  fn clone_box(&self) -> Box<dyn BusDevice> {
+    Box::new(self.clone())
+  }
+}
+
+impl Clone for Box<dyn BusDevice> {
+  fn clone(&self) -> Self {
+    self.clone_box()
+  }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Peripherals {
-  bootrom: Bootrom,
+  pub(crate) bootrom: Bootrom,
   pub cartridge: Cartridge,
   pub ppu: Ppu,
   pub apu: Apu,
@@ -24,6 +51,13 @@ pub struct Peripherals {
   pub serial: Serial,
   hram: HRam,
   wram: WRam,
+  undocumented: Undocumented,
+  #[serde(skip)]
+  pub devices: Vec<Box<dyn BusDevice>>,
+  #[serde(skip)]
+  pub(crate) read_watches: Vec<u16>,
+  #[serde(skip)]
+  read_watch_hit: Cell<Option<u16>>,
 }
 
 impl Peripherals {
@@ -32,16 +66,133 @@ impl Peripherals {
       bootrom,
       cartridge,
       ppu: Ppu::new(is_cgb),
-      apu: Apu::new(),
+      apu: Apu::new(is_cgb),
       timer: Timer::default(),
       joypad: Joypad::new(),
       serial: Serial::new(is_cgb),
       hram: HRam::new(),
       wram: WRam::new(is_cgb),
+      undocumented: Undocumented::new(is_cgb),
+      devices: Vec::new(),
+      read_watches: Vec::new(),
+      read_watch_hit: Cell::new(None),
     }
   }
 
+  pub fn fill_ram(&mut self, next: &mut impl FnMut() -> u8) {
+    self.wram.fill(next);
+    self.hram.fill(next);
+    self.ppu.fill_ram(next);
+  }
+
+  // Sets the IO registers to the values the boot ROM leaves behind, and unmaps the boot ROM.
+  // https://gbdev.io/pandocs/Power_Up_Sequence.html#hardware-registers
+  pub fn init_post_boot(&mut self, interrupts: &mut Interrupts) {
+    for (addr, val) in [
+      (0xFF50, 0x01),
+      // The APU has to be on for the other sound registers to be written.
+      (0xFF26, 0x80),
+      // NRx4 are written without the trigger bit so that no sound starts, which leaves NR52 at 0xF0 instead of 0xF1.
+      (0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF13, 0xFF), (0xFF14, 0x3F),
+      (0xFF16, 0x3F), (0xFF17, 0x00), (0xFF18, 0xFF), (0xFF19, 0x3F),
+      (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF1D, 0xFF), (0xFF1E, 0x3F),
+      (0xFF20, 0xFF), (0xFF21, 0x00), (0xFF22, 0x00), (0xFF23, 0x3F),
+      (0xFF24, 0x77), (0xFF25, 0xF3),
+      (0xFF00, 0xCF), (0xFF01, 0x00), (0xFF02, 0x7E),
+      (0xFF05, 0x00), (0xFF06, 0x00), (0xFF07, 0xF8),
+      (0xFF0F, 0xE1), (0xFFFF, 0x00),
+      (0xFF42, 0x00), (0xFF43, 0x00), (0xFF45, 0x00),
+      (0xFF47, 0xFC), (0xFF48, 0xFF), (0xFF49, 0xFF), (0xFF4A, 0x00), (0xFF4B, 0x00),
+      (0xFF40, 0x91),
+    ] {
+      self.write(interrupts, addr, val);
+    }
+    // DIV depends on how long the boot took, which varies on CGB.
+    if !self.ppu.is_cgb() {
+      self.timer.set_counter(0xABCC);
+    }
+  }
+
+  // Switches a CGB machine into DMG compatibility mode, as the boot ROM does through KEY0 for DMG cartridges.
+  // The APU keeps its CGB behaviour since that depends on the hardware, not the mode.
+  fn enter_dmg_compat(&mut self) {
+    let palettes = self.cartridge.compat_palettes();
+    self.ppu.enter_dmg_compat();
+    self.ppu.set_compat_palettes(palettes.bg, palettes.obj0, palettes.obj1);
+    self.serial.enter_dmg_compat();
+    self.wram.enter_dmg_compat();
+    self.undocumented.enter_dmg_compat();
+  }
+
+  // Borrows of the RAM regions, cheaper than reading byte by byte e.g. to diff memory between frames.
+  // See `WRam::as_slice` and `HRam::as_slice` for the layouts.
+  pub fn wram(&self) -> &[u8] {
+    self.wram.as_slice()
+  }
+  pub fn hram(&self) -> &[u8] {
+    self.hram.as_slice()
+  }
+  #[cfg(feature = "debug")]
+  pub fn wram_mut(&mut self) -> &mut [u8] {
+    self.wram.as_mut_slice()
+  }
+  #[cfg(feature = "debug")]
+  pub fn hram_mut(&mut self) -> &mut [u8] {
+    self.hram.as_mut_slice()
+  }
+
+  pub fn add_device(&mut self, device: Box<dyn BusDevice>) {
+    self.devices.push(device);
+  }
+
+  // Debugger access. Unlike `read`/`write`, these skip hardware side effects such as
+  // the PPU access windows, SRAM enable, DMA triggers and palette auto-increment.
+  // IF and IE belong to `Cpu::interrupts`, so they read as 0xFF and ignore pokes here.
+  pub fn peek(&self, addr: u16) -> u8 {
+    match addr {
+      0x0000..=0x00FF | 0x0200..=0x08FF if self.bootrom.is_active() => self.bootrom.read(addr),
+      0x0000..=0x7FFF | 0xA000..=0xBFFF => self.cartridge.peek(addr),
+      0x8000..=0x9FFF | 0xFE00..=0xFE9F | 0xFF69 | 0xFF6B => self.ppu.peek(addr),
+      0xFF0F | 0xFFFF => 0xFF,
+      _               => self.read_unwatched(&Interrupts::default(), addr),
+    }
+  }
+  pub fn poke(&mut self, addr: u16, val: u8) {
+    match addr {
+      0x0000..=0x7FFF | 0xA000..=0xBFFF => self.cartridge.poke(addr, val),
+      0x8000..=0x9FFF | 0xFE00..=0xFE9F => self.ppu.poke(addr, val),
+      0xFF40..=0xFF4B | 0xFF55 | 0xFF69 | 0xFF6B => self.ppu.poke(addr, val),
+      0xFF0F | 0xFFFF => {},
+      _               => self.write(&mut Interrupts::default(), addr, val),
+    }
+  }
+
+  // Reads of these addresses by the CPU or DMA are reported by `take_read_watch_hit`. `peek` doesn't count.
+  pub fn set_read_watch(&mut self, addr: u16) {
+    if !self.read_watches.contains(&addr) {
+      self.read_watches.push(addr);
+    }
+  }
+  pub fn clear_read_watches(&mut self) {
+    self.read_watches.clear();
+  }
+  // The last watched address read since the previous call.
+  pub fn take_read_watch_hit(&self) -> Option<u16> {
+    self.read_watch_hit.take()
+  }
+
   pub fn read(&self, interrupts: &Interrupts, addr: u16) -> u8 {
+    if !self.read_watches.is_empty() && self.read_watches.contains(&addr) {
+      self.read_watch_hit.set(Some(addr));
+    }
+    self.read_unwatched(interrupts, addr)
+  }
+  fn read_unwatched(&self, interrupts: &Interrupts, addr: u16) -> u8 {
+    if !self.devices.is_empty() {
+      if let Some(val) = self.devices.iter().find_map(|d| d.read(addr)) {
+        return val;
+      }
+    }
     match addr {
       0x0000..=0x00FF if self.bootrom.is_active() => {
         self.bootrom.read(addr)
@@ -60,16 +211,22 @@ impl Peripherals {
       0xFF0F          => interrupts.read(addr),
       0xFF10..=0xFF26 | 0xFF30..=0xFF3F => self.apu.read(addr),
       0xFF40..=0xFF4B => self.ppu.read(addr),
+      0xFF4C          => self.bootrom.read(addr),
       0xFF4F          => self.ppu.read(addr),
+      0xFF50          => self.bootrom.read(addr),
       0xFF51..=0xFF55 => self.ppu.read(addr),
-      0xFF68..=0xFF6B => self.ppu.read(addr),
+      0xFF68..=0xFF6C => self.ppu.read(addr),
       0xFF70          => self.wram.read(addr),
+      0xFF72..=0xFF75 => self.undocumented.read(addr),
       0xFF80..=0xFFFE => self.hram.read(addr),
       0xFFFF          => interrupts.read(addr),
       _               => 0xFF,
     }
   }
   pub fn write(&mut self, interrupts: &mut Interrupts, addr: u16, val: u8) {
+    if !self.devices.is_empty() && self.devices.iter_mut().any(|d| d.write(addr, val)) {
+      return;
+    }
     match addr {
       0x0000..=0x00FF => if !self.bootrom.is_active() {
         self.cartridge.write(addr, val)
@@ -85,14 +242,67 @@ impl Peripherals {
       0xFF0F          => interrupts.write(addr, val),
       0xFF10..=0xFF26 | 0xFF30..=0xFF3F => self.apu.write(addr, val),
       0xFF40..=0xFF4B => self.ppu.write(addr, val),
+      0xFF4C          => self.bootrom.write(addr, val),
       0xFF4F          => self.ppu.write(addr, val),
-      0xFF50          => self.bootrom.write(addr, val),
+      0xFF50          => {
+        let was_active = self.bootrom.is_active();
+        self.bootrom.write(addr, val);
+        if was_active && !self.bootrom.is_active() && self.ppu.is_cgb() && self.bootrom.dmg_compat() {
+          self.enter_dmg_compat();
+        }
+      },
       0xFF51..=0xFF55 => self.ppu.write(addr, val),
-      0xFF68..=0xFF6B => self.ppu.write(addr, val),
+      0xFF68..=0xFF6C => self.ppu.write(addr, val),
       0xFF70          => self.wram.write(addr, val),
+      0xFF72..=0xFF75 => self.undocumented.write(addr, val),
       0xFF80..=0xFFFE => self.hram.write(addr, val),
       0xFFFF          => interrupts.write(addr, val),
       _               => (),
     }
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::cartridge::tests::rom;
+
+  // A register at an otherwise unused IO address.
+  #[derive(Clone)]
+  struct Latch(u8);
+
+  impl BusDevice for Latch {
+    fn read(&self, addr: u16) -> Option<u8> {
+      (addr == 0xFF7F).then_some(self.0)
+    }
+    fn write(&mut self, addr: u16, val: u8) -> bool {
+      if addr == 0xFF7F {
+        self.0 = val;
+      }
+      addr == 0xFF7F
+    }
+  }
+
+  fn peripherals() -> Peripherals {
+    let cartridge = Cartridge::new(rom(0x00, 0, 0), None).unwrap();
+    Peripherals::new(Bootrom::new(), cartridge, false)
+  }
+
+  #[test]
+  fn device_claims_an_unused_address() {
+    let mut peripherals = peripherals();
+    let mut interrupts = Interrupts::default();
+    assert_eq!(peripherals.read(&interrupts, 0xFF7F), 0xFF);
+    peripherals.add_device(Box::new(Latch(0)));
+    peripherals.write(&mut interrupts, 0xFF7F, 0x42);
+    assert_eq!(peripherals.read(&interrupts, 0xFF7F), 0x42);
+    // Other addresses still reach the memory map.
+    peripherals.write(&mut interrupts, 0xC000, 0x24);
+    assert_eq!(peripherals.read(&interrupts, 0xC000), 0x24);
+    // A clone has its own copy of the device.
+    let mut snapshot = peripherals.clone();
+    snapshot.write(&mut interrupts, 0xFF7F, 0x99);
+    assert_eq!(peripherals.read(&interrupts, 0xFF7F), 0x42);
+    assert_eq!(snapshot.read(&interrupts, 0xFF7F), 0x99);
+  }
+}
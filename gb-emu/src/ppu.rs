@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use std::cmp::min;
+use std::{cmp::min, rc::Rc};
 
 use crate::{
   LCD_WIDTH,
@@ -37,7 +37,22 @@ enum Mode {
   Drawing = 3,
 }
 
-#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PpuMode {
+  HBlank,
+  VBlank,
+  OamScan,
+  Drawing,
+}
+
+// Debug switches independent of LCDC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Layer {
+  Background = 0,
+  Window = 1,
+  Sprites = 2,
+}
+
 #[derive(Clone, Copy)]
 struct Sprite {
   y: u8,
@@ -46,6 +61,8 @@ struct Sprite {
   flags: u8,
 }
 
+type HBlankCallback = Rc<dyn Fn(u8, u8, u8)>;
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Ppu {
   is_cgb: bool,
@@ -62,6 +79,7 @@ pub struct Ppu {
   wy: u8,
   wx: u8,
   wly: u8,
+  dma: u8,
   vram: Vec<u8>,
   bcps: u8,
   ocps: u8,
@@ -73,10 +91,29 @@ pub struct Ppu {
   hdma_dst: u16,
   pub hblank_dma: Option<u16>,
   pub general_dma: Option<u16>,
+  hdma5: u8,
   bg_palette_memory: Vec<u8>,
   sprite_palette_memory: Vec<u8>,
   cycles: u8,
+  // `buffer` is drawn into line by line, and copied to `front` once the frame is complete.
   pub buffer: Vec<u8>,
+  #[serde(default)]
+  front: Vec<u8>,
+  index_buffer: Vec<u8>,
+  disabled_layers: u8,
+  opri: u8,
+  color_correction: bool,
+  fine_scx: u8,
+  bg_x: u8,
+  bg_prio: Vec<(bool, bool)>,
+  // The first frame after the LCD is turned on is not displayed.
+  #[serde(default)]
+  blank_frame: bool,
+  // OAM indices of the sprites on the current line, picked during Mode 2.
+  #[serde(default)]
+  line_sprites: Vec<u8>,
+  #[serde(skip)]
+  pub hblank_callback: Option<HBlankCallback>,
 }
 
 impl Ppu {
@@ -96,6 +133,7 @@ impl Ppu {
       wy: 0,
       wx: 0,
       wly: 0,
+      dma: 0xFF,
       vram: vec![0; 0x2000],
       bcps: 0,
       ocps: 0,
@@ -107,6 +145,7 @@ impl Ppu {
       hdma_dst: 0,
       hblank_dma: None,
       general_dma: None,
+      hdma5: 0xFF,
       bg_palette_memory: vec![
         0xFF, 0x7F, 0xB5, 0x56, 0x4A, 0x29, 0x00, 0x00,
         0xFF, 0x7F, 0xB5, 0x56, 0x4A, 0x29, 0x00, 0x00,
@@ -129,6 +168,124 @@ impl Ppu {
       ],
       cycles: 20,
       buffer: vec![0; LCD_PIXELS*4],
+      front: vec![0; LCD_PIXELS*4],
+      index_buffer: vec![0; LCD_PIXELS],
+      disabled_layers: 0,
+      opri: 0,
+      color_correction: false,
+      fine_scx: 0,
+      bg_x: 0,
+      bg_prio: vec![(false, false); LCD_WIDTH],
+      blank_frame: false,
+      line_sprites: Vec::new(),
+      hblank_callback: None,
+    }
+  }
+  // The last completed frame, which unlike `buffer` is never partially drawn.
+  pub fn front_buffer(&self) -> &[u8] {
+    // States saved before the front buffer existed have none until the next VBlank.
+    if self.front.is_empty() {
+      &self.buffer
+    } else {
+      &self.front
+    }
+  }
+  // The 0-3 shade of each pixel after BGP/OBP0/OBP1 on DMG, or the color number within its palette on CGB.
+  pub fn buffer_indices(&self) -> Box<[u8; LCD_PIXELS]> {
+    self.index_buffer.clone().into_boxed_slice().try_into().unwrap()
+  }
+  // FNV-1a over the RGBA buffer. Stable across runs and platforms, so it can be compared with golden values.
+  pub fn frame_hash(&self) -> u64 {
+    self.front_buffer().iter().fold(0xcbf29ce484222325, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+  }
+  pub fn fill_ram(&mut self, next: &mut impl FnMut() -> u8) {
+    self.vram.iter_mut()
+      .chain(self.vram2.iter_mut())
+      .chain(self.oam.iter_mut())
+      .for_each(|b| *b = next());
+  }
+  pub fn set_layer_enabled(&mut self, layer: Layer, on: bool) {
+    if on {
+      self.disabled_layers &= !(1 << layer as u8);
+    } else {
+      self.disabled_layers |= 1 << layer as u8;
+    }
+  }
+  pub fn layer_enabled(&self, layer: Layer) -> bool {
+    self.disabled_layers & (1 << layer as u8) == 0
+  }
+  // Called with (LY, SCX, SCY) when each line enters HBlank.
+  pub fn set_hblank_callback(&mut self, callback: HBlankCallback) {
+    self.hblank_callback = Some(callback);
+  }
+  // Approximates the colors of the CGB LCD instead of the raw RGB555 values.
+  pub fn set_color_correction(&mut self, on: bool) {
+    self.color_correction = on;
+  }
+  pub fn mode(&self) -> PpuMode {
+    match self.mode {
+      Mode::HBlank  => PpuMode::HBlank,
+      Mode::VBlank  => PpuMode::VBlank,
+      Mode::OamScan => PpuMode::OamScan,
+      Mode::Drawing => PpuMode::Drawing,
+    }
+  }
+  pub fn current_scanline(&self) -> u8 {
+    self.ly
+  }
+  // The dot within the current scanline (0-455), in steps of one M-cycle.
+  pub fn dot(&self) -> u16 {
+    let elapsed = match self.mode {
+      Mode::OamScan => 20 - self.cycles as u16,
+      Mode::Drawing => 20 + 43 - self.cycles as u16,
+      Mode::HBlank  => 20 + 43 + 51 - self.cycles as u16,
+      Mode::VBlank  => 114 - self.cycles as u16,
+    };
+    elapsed * 4
+  }
+  pub fn is_cgb(&self) -> bool {
+    self.is_cgb
+  }
+  pub fn enter_dmg_compat(&mut self) {
+    self.is_cgb = false;
+  }
+  // Installs the colors used for DMG shades, as the CGB boot ROM does for DMG games.
+  pub fn set_compat_palettes(&mut self, bg: [u16; 4], obj0: [u16; 4], obj1: [u16; 4]) {
+    for i in 0..4 {
+      self.bg_palette_memory[i << 1..(i + 1) << 1].copy_from_slice(&bg[i].to_le_bytes());
+      self.sprite_palette_memory[i << 1..(i + 1) << 1].copy_from_slice(&obj0[i].to_le_bytes());
+      self.sprite_palette_memory[8 + (i << 1)..8 + ((i + 1) << 1)].copy_from_slice(&obj1[i].to_le_bytes());
+    }
+  }
+  // Reads VRAM, OAM and palette memory regardless of the current mode.
+  pub fn peek(&self, addr: u16) -> u8 {
+    match addr {
+      0x8000..=0x9FFF => if self.vbk & 1 > 0 {
+        self.vram2[addr as usize & 0x1FFF]
+      } else {
+        self.vram[addr as usize & 0x1FFF]
+      },
+      0xFE00..=0xFE9F => self.oam[addr as usize & 0xFF],
+      0xFF69          => self.bg_palette_memory[self.bcps as usize & 0x3F],
+      0xFF6B          => self.sprite_palette_memory[self.ocps as usize & 0x3F],
+      _               => self.read(addr),
+    }
+  }
+  // Writes regardless of the current mode, without starting DMA or auto-incrementing palette indices.
+  pub fn poke(&mut self, addr: u16, val: u8) {
+    match addr {
+      0x8000..=0x9FFF => if self.vbk & 1 > 0 {
+        self.vram2[addr as usize & 0x1FFF] = val;
+      } else {
+        self.vram[addr as usize & 0x1FFF] = val;
+      },
+      0xFE00..=0xFE9F => self.oam[addr as usize & 0xFF] = val,
+      0xFF41          => self.stat = val & 0x7C,
+      0xFF44          => self.ly = val,
+      0xFF46 | 0xFF55 => {},
+      0xFF69          => self.bg_palette_memory[self.bcps as usize & 0x3F] = val,
+      0xFF6B          => self.sprite_palette_memory[self.ocps as usize & 0x3F] = val,
+      _               => self.write(addr, val),
     }
   }
   pub fn read(&self, addr: u16) -> u8 {
@@ -142,45 +299,64 @@ impl Ppu {
           self.vram[addr as usize & 0x1FFF]
         }
       },
-      0xFE00..=0xFE9F => if self.mode == Mode::Drawing || self.mode == Mode::OamScan {
+      // OAM DMA owns the whole OAM while it runs, whatever the mode.
+      0xFE00..=0xFE9F => if self.oam_dma.is_some() || self.mode == Mode::Drawing || self.mode == Mode::OamScan {
         0xFF
       } else {
-        if self.oam_dma.is_some() {
-          0xFF
-        } else {
-          self.oam[addr as usize & 0xFF]
-        }
+        self.oam[addr as usize & 0xFF]
       },
       0xFF40          => self.lcdc,
-      0xFF41          => 0x80 | self.stat | self.mode as u8,
+      // The mode bits read 0 while the LCD is off.
+      0xFF41          => if self.lcdc & PPU_ENABLE > 0 {
+        0x80 | self.stat | self.mode as u8
+      } else {
+        0x80 | self.stat
+      },
       0xFF42          => self.scy,
       0xFF43          => self.scx,
       0xFF44          => self.ly,
       0xFF45          => self.lyc,
-      0xFF46          => 0xFF,
+      // The last written source is readable.
+      0xFF46          => self.dma,
       0xFF47          => self.bgp,
       0xFF48          => self.obp0,
       0xFF49          => self.obp1,
       0xFF4A          => self.wy,
       0xFF4B          => self.wx,
       0xFF4F          => self.vbk | 0xFE,
-      0xFF55          => if let Some(len) = self.hblank_dma {
-        0x80 | (((len >> 4) - 1) & 0x7F) as u8
+      // The HDMA source and destination are write-only.
+      0xFF51..=0xFF54 => 0xFF,
+      // Bit 7 is 0 while an HBlank DMA is active.
+      0xFF55          => if let Some(len) = self.hblank_dma.or(self.general_dma) {
+        ((len.wrapping_sub(1) >> 4) & 0x7F) as u8
+      } else {
+        self.hdma5
+      },
+      0xFF68          => if self.is_cgb {
+        self.bcps | 0x40
       } else {
         0xFF
       },
-      0xFF68          => self.bcps,
       0xFF69          => if self.mode == Mode::Drawing {
         0xFF
       } else {
         self.bg_palette_memory[self.bcps as usize & 0x3F]
       },
-      0xFF6A          => self.ocps,
+      0xFF6A          => if self.is_cgb {
+        self.ocps | 0x40
+      } else {
+        0xFF
+      },
       0xFF6B          => if self.mode == Mode::Drawing {
         0xFF
       } else {
         self.sprite_palette_memory[self.ocps as usize & 0x3F]
       },
+      0xFF6C          => if self.is_cgb {
+        0xFE | self.opri
+      } else {
+        0xFF
+      },
       _               => unreachable!(),
     }
   }
@@ -193,12 +369,19 @@ impl Ppu {
           self.vram[addr as usize & 0x1FFF] = val;
         }
       },
-      0xFE00..=0xFE9F => if self.mode != Mode::Drawing && self.mode != Mode::OamScan {
-        if self.oam_dma.is_none() {
-          self.oam[addr as usize & 0xFF] = val;
+      0xFE00..=0xFE9F => if self.oam_dma.is_none() && self.mode != Mode::Drawing && self.mode != Mode::OamScan {
+        self.oam[addr as usize & 0xFF] = val;
+      },
+      0xFF40          => {
+        if (self.lcdc ^ val) & PPU_ENABLE > 0 {
+          if val & PPU_ENABLE > 0 {
+            self.turn_on();
+          } else {
+            self.turn_off();
+          }
         }
+        self.lcdc = val;
       },
-      0xFF40          => self.lcdc = val,
       0xFF41          => self.stat = (self.stat & LYC_EQ_LY) | (val & 0xF8),
       0xFF42          => self.scy = val,
       0xFF43          => self.scx = val,
@@ -206,6 +389,7 @@ impl Ppu {
       0xFF45          => self.lyc = val,
       0xFF46          => {
         assert!(val <= 0xDF);
+        self.dma = val;
         self.oam_dma = Some((val as u16) << 8);
       },
       0xFF47          => self.bgp = val,
@@ -218,12 +402,18 @@ impl Ppu {
       0xFF52          => self.hdma_src = (self.hdma_src & 0xFF00) | (val as u16 & 0xF0),
       0xFF53          => self.hdma_dst = (self.hdma_dst & 0xF0) | (val as u16 & 0x1F) << 8,
       0xFF54          => self.hdma_dst = (self.hdma_dst & 0x1F00) | (val as u16 & 0xF0),
-      0xFF55          => if val & 0x80 > 0 {
+      0xFF55          => if let (Some(len), 0) = (self.hblank_dma, val & 0x80) {
+        // Cancels the active HBlank DMA. The remaining length stays readable.
+        self.hdma5 = 0x80 | (((len >> 4) - 1) & 0x7F) as u8;
+        self.hblank_dma = None;
+      } else if val & 0x80 > 0 {
+        self.hdma5 = 0xFF;
         self.hblank_dma = Some(min(
           0x2000 - self.hdma_dst,
           ((val as u16 & 0x7F) + 1) << 4)
         );
       } else {
+        self.hdma5 = 0xFF;
         self.general_dma = Some(min(
           0x2000 - self.hdma_dst,
           ((val as u16 & 0x7F) + 1) << 4)
@@ -231,6 +421,7 @@ impl Ppu {
         self.hblank_dma = None;
       }
       0xFF68          => self.bcps = val,
+      // Palette memory is locked during Mode 3, but the index still auto-increments when the write is dropped.
       0xFF69          => {
         if self.mode != Mode::Drawing {
           self.bg_palette_memory[self.bcps as usize & 0x3F] = val;
@@ -248,15 +439,46 @@ impl Ppu {
           self.ocps = (self.ocps & 0xC0) | (((self.ocps & 0x3F) + 1) & 0x3F);
         }
       },
+      0xFF6C          => if self.is_cgb {
+        self.opri = val & 1;
+      },
       _               => unreachable!(),
     }
   }
+  // The screen goes blank and LY is held at 0 until the LCD is turned on again.
+  fn turn_off(&mut self) {
+    self.ly = 0;
+    self.wly = 0;
+    self.mode = Mode::HBlank;
+    self.buffer.fill(0xFF);
+    self.index_buffer.fill(0);
+    self.front.clone_from(&self.buffer);
+  }
+  // The PPU restarts at the beginning of line 0, and draws nothing until the next frame.
+  fn turn_on(&mut self) {
+    self.ly = 0;
+    self.wly = 0;
+    self.mode = Mode::OamScan;
+    self.cycles = 20;
+    self.blank_frame = true;
+    self.stat = (self.stat & !LYC_EQ_LY) | if self.lyc == 0 { LYC_EQ_LY } else { 0 };
+  }
   pub fn emulate_cycle(&mut self, interrupts: &mut Interrupts) -> bool {
     if self.lcdc & PPU_ENABLE == 0 {
       return false;
     }
 
     self.cycles -= 1;
+    // LY reads 153 only for the first M-cycle of the last line, and 0 for the rest of it.
+    if self.mode == Mode::VBlank && self.ly == 153 && self.cycles == 113 {
+      self.ly = 0;
+      self.check_lyc_eq_ly(interrupts);
+    }
+    if self.mode == Mode::Drawing && !self.blank_frame {
+      // The first 12 dots of Mode 3 fetch the first tile, then a pixel is shifted out every dot.
+      let dots = (43 - self.cycles as usize) * 4;
+      self.render_bg(min(dots.saturating_sub(12), LCD_WIDTH));
+    }
     if self.cycles > 0 {
       return false;
     }
@@ -274,6 +496,7 @@ impl Ppu {
         } else {
           self.mode = Mode::VBlank;
           self.cycles = 114;
+          self.front.clone_from(&self.buffer);
           interrupts.irq(interrupts::VBLANK);
           if self.stat & VBLANK_INT > 0 {
             interrupts.irq(interrupts::STAT);
@@ -282,10 +505,10 @@ impl Ppu {
         self.check_lyc_eq_ly(interrupts);
       },
       Mode::VBlank => {
-        self.ly += 1;
-        if self.ly > 153 {
+        // LY already became 0 during line 153.
+        if self.ly == 0 {
           ret = true;
-          self.ly = 0;
+          self.blank_frame = false;
           self.wly = 0;
           self.mode = Mode::OamScan;
           self.cycles = 20;
@@ -293,17 +516,27 @@ impl Ppu {
             interrupts.irq(interrupts::STAT);
           }
         } else {
+          self.ly += 1;
           self.cycles = 114;
+          self.check_lyc_eq_ly(interrupts);
         }
-        self.check_lyc_eq_ly(interrupts);
       },
       Mode::OamScan => {
+        self.scan_oam();
         self.mode = Mode::Drawing;
         self.cycles = 43;
+        // The fine scroll is latched when Mode 3 starts, while the coarse scroll is read on each tile fetch.
+        self.fine_scx = self.scx & 7;
+        self.bg_x = 0;
       },
       Mode::Drawing => {
-        self.render();
+        if !self.blank_frame {
+          self.render();
+        }
         self.mode = Mode::HBlank;
+        if let Some(callback) = self.hblank_callback.as_ref() {
+          callback(self.ly, self.scx, self.scy);
+        }
         self.cycles = 51;
         if self.stat & HBLANK_INT > 0 {
           interrupts.irq(interrupts::STAT);
@@ -312,6 +545,29 @@ impl Ppu {
     }
     ret
   }
+  // The OAM row the DMG PPU is scanning, if any. CGB is not affected by the OAM corruption bug.
+  pub fn oam_scan_row(&self) -> Option<usize> {
+    if self.is_cgb || self.lcdc & PPU_ENABLE == 0 || self.mode != Mode::OamScan {
+      return None;
+    }
+    Some(20 - self.cycles as usize)
+  }
+  // https://gbdev.io/pandocs/OAM_Corruption_Bug.html
+  pub fn trigger_oam_corruption(&mut self, row: usize) {
+    if row == 0 || row >= 20 {
+      return;
+    }
+    let (cur, prev) = (row << 3, (row - 1) << 3);
+    for i in 0..2 {
+      let a = self.oam[cur + i];
+      let b = self.oam[prev + i];
+      let c = self.oam[prev + 4 + i];
+      self.oam[cur + i] = ((a ^ c) & (b ^ c)) ^ c;
+    }
+    for i in 2..8 {
+      self.oam[cur + i] = self.oam[prev + i];
+    }
+  }
   pub fn oam_dma_emulate_cycle(&mut self, val: u8) {
     if let Some(addr) = self.oam_dma {
       if self.mode != Mode::Drawing && self.mode != Mode::OamScan {
@@ -341,33 +597,50 @@ impl Ppu {
       }
     }
   }
-  pub fn general_dma_emulate_cycle(&mut self, vals: Vec<u8>) {
+  // General DMA copies 2 bytes per M-cycle, so 0x10 bytes take 8 M-cycles while the CPU is stalled.
+  pub fn general_dma_emulate_cycle(&mut self, vals: [u8; 2]) {
     if let Some(len) = self.general_dma {
-      assert!(len as usize == vals.len());
-      assert!(self.hdma_dst + len <= 0x2000);
-      for i in 0..len as usize {
+      assert!(self.hdma_dst + 2 <= 0x2000);
+      for (i, &val) in vals.iter().enumerate() {
         if self.vbk & 1 > 0 {
-          self.vram2[self.hdma_dst as usize + i] = vals[i];
+          self.vram2[self.hdma_dst as usize + i] = val;
         } else {
-          self.vram[self.hdma_dst as usize + i] = vals[i];
+          self.vram[self.hdma_dst as usize + i] = val;
         }
       }
-      self.general_dma = None;
+      self.hdma_src += 2;
+      self.hdma_dst += 2;
+      self.general_dma = Some(len - 2).filter(|&x| x > 0);
     }
   }
+  // The background is drawn progressively during Mode 3 so that mid-line register writes take effect.
+  // The window and sprites are still drawn at the end of the line.
   fn render(&mut self) {
-    let mut bg_prio: [(bool, bool); LCD_WIDTH] = [(false, false); LCD_WIDTH];
-    self.render_bg(&mut bg_prio);
+    self.render_bg(LCD_WIDTH);
+    let mut bg_prio: [(bool, bool); LCD_WIDTH] = self.bg_prio.as_slice().try_into().unwrap();
     self.render_window(&mut bg_prio);
     self.render_sprite(&bg_prio);
   }
-  fn render_bg(&mut self, bg_prio: &mut [(bool, bool); LCD_WIDTH]) {
+  // Draws the background up to the `to`th pixel of the line.
+  fn render_bg(&mut self, to: usize) {
+    let from = self.bg_x as usize;
+    if from >= to {
+      return;
+    }
+    self.bg_x = to as u8;
+    self.bg_prio[from..to].fill((false, false));
+    if !self.layer_enabled(Layer::Background) {
+      let line = LCD_WIDTH * self.ly as usize;
+      self.buffer[(line + from) * 4..(line + to) * 4].fill(0xFF);
+      self.index_buffer[line + from..line + to].fill(0);
+      return;
+    }
     if self.lcdc & BG_WINDOW_ENABLE == 0 && !self.is_cgb {
       return;
     }
     let y = self.ly.wrapping_add(self.scy);
-    for i in 0..LCD_WIDTH {
-      let x = (i as u8).wrapping_add(self.scx);
+    for i in from..to {
+      let x = (i as u8).wrapping_add(self.fine_scx).wrapping_add(self.scx & !7);
       let tile_idx = self.get_tile_idx_from_tile_map(
         self.lcdc & BG_TILE_MAP > 0,
         y >> 3, x >> 3
@@ -395,7 +668,8 @@ impl Ppu {
       for j in 0..4 {
         self.buffer[(LCD_WIDTH * self.ly as usize + i) * 4 + j] = color[j];
       }
-      bg_prio[i] = (attr & OBJ2BG_PRIORITY > 0, pixel > 0);
+      self.index_buffer[LCD_WIDTH * self.ly as usize + i] = pixel;
+      self.bg_prio[i] = (attr & OBJ2BG_PRIORITY > 0, pixel > 0);
     }
   }
   fn render_window(&mut self, bg_prio: &mut [(bool, bool); LCD_WIDTH]) {
@@ -405,13 +679,21 @@ impl Ppu {
     if self.lcdc & BG_WINDOW_ENABLE == 0 && !self.is_cgb {
       return;
     }
+    if !self.layer_enabled(Layer::Window) {
+      // Keep the internal line counter running as if the window was drawn.
+      self.wly += ((self.wx as usize) < LCD_WIDTH + 7) as u8;
+      return;
+    }
     let mut wly_add = 0;
     let y = self.wly;
+    // The window starts at WX - 7. With WX < 7, its first 7 - WX columns are cut off at the left edge,
+    // and WX = 166 shows only its first column. The stutter of WX = 0 with SCX & 7 is not emulated.
+    let wx = self.wx as usize;
     for i in 0..LCD_WIDTH {
-      let (x, overflow) = (i as u8).overflowing_sub(self.wx.wrapping_sub(7));
-      if overflow {
+      if i + 7 < wx {
         continue;
       }
+      let x = (i + 7 - wx) as u8;
       wly_add = 1;
       let tile_idx = self.get_tile_idx_from_tile_map(
         (self.lcdc & WINDOW_TILE_MAP) > 0,
@@ -440,36 +722,57 @@ impl Ppu {
       for j in 0..4 {
         self.buffer[(LCD_WIDTH * self.ly as usize + i) * 4 + j] = color[j];
       }
+      self.index_buffer[LCD_WIDTH * self.ly as usize + i] = pixel;
       bg_prio[i] = (attr & OBJ2BG_PRIORITY > 0, pixel > 0);
     }
     self.wly += wly_add;
   }
+  // Picks the first 10 sprites on the line in OAM order, regardless of X, as Mode 2 does.
+  fn scan_oam(&mut self) {
+    let size = if self.lcdc & SPRITE_SIZE > 0 { 16 } else { 8 };
+    let ly = self.ly;
+    self.line_sprites = self.oam.chunks(4)
+      .enumerate()
+      .filter(|(_, attrs)| ly.wrapping_sub(attrs[0].wrapping_sub(16)) < size)
+      .map(|(i, _)| i as u8)
+      .take(10)
+      .collect();
+  }
+  // OAM indices of the sprites selected for the current line.
+  pub fn line_sprites(&self) -> &[u8] {
+    &self.line_sprites
+  }
   fn render_sprite(&mut self, bg_prio: &[(bool, bool); LCD_WIDTH]) {
-    if self.lcdc & SPRITE_ENABLE == 0 {
+    if self.lcdc & SPRITE_ENABLE == 0 || !self.layer_enabled(Layer::Sprites) {
       return;
     }
     let size = if self.lcdc & SPRITE_SIZE > 0 { 16 } else { 8 };
 
-    let mut sprites: Vec<Sprite> = unsafe {
-      std::mem::transmute::<[u8; 0xA0], [Sprite; 40]>(
-        self.oam.clone().try_into().unwrap()
-      )
-    }.into_iter().filter_map(|mut sprite| {
-      sprite.y = sprite.y.wrapping_sub(16);
-      sprite.x = sprite.x.wrapping_sub(8);
-      if self.ly.wrapping_sub(sprite.y) < size {
-        Some(sprite)
-      } else {
-        None
+    let mut sprites: Vec<Sprite> = self.line_sprites.iter().map(|&i| {
+      let attrs = &self.oam[i as usize * 4..i as usize * 4 + 4];
+      Sprite {
+        y: attrs[0].wrapping_sub(16),
+        x: attrs[1].wrapping_sub(8),
+        tile_idx: attrs[2],
+        flags: attrs[3],
       }
-    }).take(10).collect();
+    }).collect();
+    // Draw from the lowest priority so that higher priority sprites end up on top.
+    // The selection above is already fixed, so reordering here never changes which sprites are drawn.
     sprites.reverse();
-    if !self.is_cgb {
+    // CGB uses OAM priority unless OPRI selects coordinate priority, which DMG always uses.
+    if !self.is_cgb || self.opri & 1 > 0 {
+      // Smaller X wins, then smaller OAM index (the sort is stable).
       sprites.sort_by(|&a, &b| b.x.cmp(&a.x));
     }
 
     for sprite in sprites {
-      let palette = sprite.flags & 0b111;
+      // In DMG mode, OBP0/OBP1 select the first or second object compat palette.
+      let palette = if self.is_cgb {
+        sprite.flags & 0b111
+      } else {
+        (sprite.flags & PALETTE > 0) as u8
+      };
       let mut tile_idx = sprite.tile_idx as usize;
       let mut row = if sprite.flags & Y_FLIP > 0 {
         size - 1 - self.ly.wrapping_sub(sprite.y)
@@ -477,7 +780,8 @@ impl Ppu {
         self.ly.wrapping_sub(sprite.y)
       };
 
-      // if the size is 16 and it is second tile
+      // An 8x16 sprite is flipped as a whole, so the row is flipped over all 16 lines
+      // before choosing the tile. This swaps the top and bottom tiles as well.
       if size == 16 {
         tile_idx &= 0xFE;
       }
@@ -490,7 +794,9 @@ impl Ppu {
         } else {
           col
         };
-        let mut pixel = self.get_pixel_from_tile(tile_idx, row, col_flipped, sprite.flags & BANK > 0);
+        // DMG games may leave the CGB-only bank bit set, which has to be ignored in DMG mode like the palette bits.
+        let mut pixel = self.get_pixel_from_tile(tile_idx, row, col_flipped, self.is_cgb && sprite.flags & BANK > 0);
+        // X = 1..=7 wraps around to show the right part at the left edge, X = 0 or X >= 168 is fully hidden.
         let i = sprite.x.wrapping_add(col) as usize;
         if i < LCD_WIDTH && pixel > 0 {
           if !self.is_cgb {
@@ -503,6 +809,7 @@ impl Ppu {
             for j in 0..4 {
               self.buffer[(LCD_WIDTH * self.ly as usize + i) * 4 + j] = color[j];
             }
+            self.index_buffer[LCD_WIDTH * self.ly as usize + i] = pixel;
           }
         }
       }
@@ -531,6 +838,9 @@ impl Ppu {
     (((high >> c) & 1) << 1) | ((low >> c) & 1)
   }
   fn get_bg_attr(&self, tile_map: bool, row: u8, col: u8) -> u8 {
+    if !self.is_cgb {
+      return 0;
+    }
     let start_addr: usize = 0x1800 | ((tile_map as usize) << 10);
     self.vram2[start_addr | ((((row as usize) << 5) + col as usize) & 0x3FF)]
   }
@@ -544,6 +854,16 @@ impl Ppu {
     let rgb555 = 
       (palette_memory[((palette as usize) << 3) + ((pixel as usize) << 1)] as u16) |
       (palette_memory[((palette as usize) << 3) + ((pixel as usize) << 1) + 1] as u16) << 8;
+    if self.color_correction {
+      // The matrix used by Gambatte
+      let r = rgb555 & 0x1F;
+      let g = (rgb555 >> 5) & 0x1F;
+      let b = (rgb555 >> 10) & 0x1F;
+      rgba[0] = ((r * 13 + g * 2 + b) >> 1) as u8;
+      rgba[1] = ((g * 3 + b) << 1) as u8;
+      rgba[2] = ((r * 3 + g * 2 + b * 11) >> 1) as u8;
+      return rgba;
+    }
     for i in 0..3 {
       rgba[i] = ((rgb555 >> (5 * i)) & 0x1F) as u8;
       rgba[i] = (rgba[i] << 3) | (rgba[i] >> 2);
@@ -561,3 +881,425 @@ impl Ppu {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn enabled_ppu(is_cgb: bool, lcdc: u8) -> Ppu {
+    let mut ppu = Ppu::new(is_cgb);
+    ppu.lcdc = PPU_ENABLE | lcdc;
+    ppu.bgp = 0xE4;
+    ppu.obp0 = 0xE4;
+    ppu.obp1 = 0xE4;
+    ppu
+  }
+
+  // Fills every row of the tile with the same low and high bytes.
+  fn set_tile(ppu: &mut Ppu, tile_idx: usize, low: u8, high: u8) {
+    for row in 0..8 {
+      ppu.vram[(tile_idx << 4) + (row << 1)] = low;
+      ppu.vram[(tile_idx << 4) + (row << 1) + 1] = high;
+    }
+  }
+
+  fn set_sprite(ppu: &mut Ppu, i: usize, y: u8, x: u8, tile_idx: u8, flags: u8) {
+    ppu.oam[i * 4..i * 4 + 4].copy_from_slice(&[y, x, tile_idx, flags]);
+  }
+
+  // Runs Mode 2 and Mode 3 of line `ly`.
+  fn draw_line(ppu: &mut Ppu, ly: u8) {
+    let mut interrupts = Interrupts::default();
+    ppu.ly = ly;
+    ppu.mode = Mode::OamScan;
+    ppu.cycles = 20;
+    ppu.bg_x = 0;
+    while ppu.mode != Mode::HBlank {
+      ppu.emulate_cycle(&mut interrupts);
+    }
+  }
+
+  fn line(ppu: &Ppu, ly: u8) -> &[u8] {
+    &ppu.index_buffer[LCD_WIDTH * ly as usize..LCD_WIDTH * (ly as usize + 1)]
+  }
+
+  #[test]
+  fn ten_sprites_per_line_in_oam_order() {
+    let mut ppu = enabled_ppu(false, SPRITE_ENABLE);
+    set_tile(&mut ppu, 1, 0xFF, 0xFF);
+    // Later sprites are further left, so that X can't be what picks them.
+    for i in 0..12 {
+      set_sprite(&mut ppu, i, 16, 8 + (11 - i as u8) * 12, 1, 0);
+    }
+    draw_line(&mut ppu, 0);
+    for i in 0..12 {
+      let x = (11 - i) * 12;
+      assert_eq!(line(&ppu, 0)[x..x + 8], [if i < 10 { 3 } else { 0 }; 8], "sprite {}", i);
+    }
+  }
+  #[test]
+  fn indices_match_the_rgba_buffer() {
+    let mut ppu = enabled_ppu(false, BG_WINDOW_ENABLE | TILE_DATA_ADDRESSING_MODE);
+    // Raw colors 0, 1, 2, 3 twice, reversed by BGP.
+    set_tile(&mut ppu, 0, 0b0101_0101, 0b0011_0011);
+    ppu.bgp = 0b00_01_10_11;
+    draw_line(&mut ppu, 0);
+    assert_eq!(line(&ppu, 0)[..8], [3, 2, 1, 0, 3, 2, 1, 0]);
+    for (i, &index) in line(&ppu, 0).iter().enumerate() {
+      assert_eq!(ppu.buffer[i * 4..i * 4 + 4], ppu.get_color_from_palette_memory(0, index, false), "pixel {}", i);
+    }
+  }
+  #[test]
+  fn dmg_sprites_use_the_compat_object_palettes() {
+    let mut ppu = enabled_ppu(false, SPRITE_ENABLE);
+    let white = [0x7FFF; 4];
+    ppu.set_compat_palettes(white, [0x7FFF, 0, 0, 0x7C00], [0x7FFF, 0, 0, 0x001F]);
+    set_tile(&mut ppu, 1, 0xFF, 0xFF);
+    set_sprite(&mut ppu, 0, 16, 8, 1, 0);
+    set_sprite(&mut ppu, 1, 16, 16, 1, PALETTE);
+    draw_line(&mut ppu, 0);
+    // Blue from the first object palette, then red from the second.
+    assert_eq!(ppu.buffer[..4], [0x00, 0x00, 0xFF, 0xFF]);
+    assert_eq!(ppu.buffer[8 * 4..8 * 4 + 4], [0xFF, 0x00, 0x00, 0xFF]);
+  }
+  #[test]
+  fn sprites_clipped_at_the_edges() {
+    for (x, visible) in [(0, 0..0), (1, 0..1), (7, 0..7), (160, 152..160), (168, 0..0)] {
+      let mut ppu = enabled_ppu(false, SPRITE_ENABLE);
+      // Color 1, except for color 3 in the rightmost column.
+      set_tile(&mut ppu, 1, 0xFF, 0x01);
+      set_sprite(&mut ppu, 0, 16, x, 1, 0);
+      draw_line(&mut ppu, 0);
+      for (i, &pixel) in line(&ppu, 0).iter().enumerate() {
+        let expected = if i + 1 == visible.end { 3 } else if visible.contains(&i) { 1 } else { 0 };
+        assert_eq!(pixel, expected, "X={} pixel {}", x, i);
+      }
+    }
+  }
+
+  #[test]
+  fn hidden_sprites_use_up_the_line() {
+    let mut ppu = enabled_ppu(false, SPRITE_ENABLE);
+    set_tile(&mut ppu, 1, 0xFF, 0xFF);
+    for i in 0..10 {
+      set_sprite(&mut ppu, i, 16, if i % 2 == 0 { 0 } else { 168 }, 1, 0);
+    }
+    set_sprite(&mut ppu, 10, 16, 88, 1, 0);
+    draw_line(&mut ppu, 0);
+    assert!(line(&ppu, 0).iter().all(|&pixel| pixel == 0));
+  }
+  #[test]
+  fn disabling_sprites_leaves_the_background() {
+    let draw = |lcdc: u8, sprites: bool| {
+      let mut ppu = enabled_ppu(false, BG_WINDOW_ENABLE | TILE_DATA_ADDRESSING_MODE | lcdc);
+      set_tile(&mut ppu, 0, 0b0101_0101, 0b0011_0011);
+      set_tile(&mut ppu, 1, 0xFF, 0xFF);
+      set_sprite(&mut ppu, 0, 16, 20, 1, 0);
+      ppu.set_layer_enabled(Layer::Sprites, sprites);
+      draw_line(&mut ppu, 0);
+      ppu.buffer
+    };
+    let background = draw(0, true);
+    assert_eq!(draw(SPRITE_ENABLE, false), background);
+    assert_ne!(draw(SPRITE_ENABLE, true), background);
+  }
+  #[test]
+  fn oam_corruption_pattern() {
+    let mut ppu = enabled_ppu(false, 0);
+    ppu.oam[0x10..0x18].copy_from_slice(&[0x0F, 0xF0, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+    ppu.oam[0x18..0x20].copy_from_slice(&[0xAA, 0x55, 0x77, 0x77, 0x77, 0x77, 0x77, 0x77]);
+    let before = ppu.oam.clone();
+    ppu.trigger_oam_corruption(3);
+    // The first word becomes ((a ^ c) & (b ^ c)) ^ c, with a from this row and b, c from the first and third word
+    // of the previous row. The other 3 words are copied from the previous row.
+    assert_eq!(ppu.oam[0x18..0x20], [0x2B, 0x54, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+    assert_eq!(ppu.oam[..0x18], before[..0x18]);
+    assert_eq!(ppu.oam[0x20..], before[0x20..]);
+    // The first row is never corrupted.
+    ppu.trigger_oam_corruption(0);
+    assert_eq!(ppu.oam[..8], before[..8]);
+  }
+
+  #[test]
+  fn oam_corruption_only_during_dmg_oam_scan() {
+    let mut ppu = enabled_ppu(false, 0);
+    ppu.cycles = 17;
+    assert_eq!(ppu.oam_scan_row(), Some(3));
+    ppu.mode = Mode::HBlank;
+    assert_eq!(ppu.oam_scan_row(), None);
+    let mut ppu = enabled_ppu(true, 0);
+    ppu.cycles = 17;
+    assert_eq!(ppu.oam_scan_row(), None);
+  }
+  #[test]
+  fn cgb_sprite_priority_follows_opri() {
+    for (opri, winner) in [(0, 1), (1, 2)] {
+      let mut ppu = enabled_ppu(true, SPRITE_ENABLE);
+      ppu.write(0xFF6C, opri);
+      set_tile(&mut ppu, 1, 0xFF, 0x00);
+      set_tile(&mut ppu, 2, 0x00, 0xFF);
+      // Sprite 0 is first in OAM, sprite 1 is further left. They overlap at pixels 8-11.
+      set_sprite(&mut ppu, 0, 16, 16, 1, 0);
+      set_sprite(&mut ppu, 1, 16, 12, 2, 0);
+      draw_line(&mut ppu, 0);
+      assert_eq!(line(&ppu, 0)[8..12], [winner; 4], "OPRI={}", opri);
+    }
+  }
+  #[test]
+  fn cancel_hblank_dma() {
+    let mut ppu = enabled_ppu(true, 0);
+    ppu.write(0xFF51, 0xC0);
+    ppu.write(0xFF52, 0x00);
+    ppu.write(0xFF53, 0x00);
+    ppu.write(0xFF54, 0x00);
+    // 4 blocks of 0x10 bytes.
+    ppu.write(0xFF55, 0x83);
+    assert_eq!(ppu.read(0xFF55), 0x03);
+    ppu.mode = Mode::HBlank;
+    ppu.cycles = 51;
+    ppu.hblank_dma_emulate_cycle([0xAA; 0x10]);
+    assert_eq!(ppu.read(0xFF55), 0x02);
+    ppu.write(0xFF55, 0x00);
+    assert_eq!(ppu.hblank_dma, None);
+    assert_eq!(ppu.general_dma, None);
+    // Bit 7 reads 1 once stopped, with the remaining length kept.
+    assert_eq!(ppu.read(0xFF55), 0x82);
+    assert_eq!(ppu.vram[..0x10], [0xAA; 0x10]);
+    assert_eq!(ppu.vram[0x10], 0x00);
+  }
+  #[test]
+  fn ly_reads_0_early_in_line_153() {
+    let mut ppu = enabled_ppu(false, 0);
+    let mut interrupts = Interrupts::default();
+    ppu.write(0xFF45, 0);
+    ppu.write(0xFF41, LYC_EQ_LY_INT);
+    ppu.ly = 152;
+    ppu.mode = Mode::VBlank;
+    ppu.cycles = 1;
+    ppu.emulate_cycle(&mut interrupts);
+    assert_eq!(ppu.read(0xFF44), 153);
+    assert_eq!(interrupts.intr_flags, 0);
+    ppu.emulate_cycle(&mut interrupts);
+    assert_eq!(ppu.read(0xFF44), 0);
+    assert_eq!(interrupts.intr_flags, interrupts::STAT);
+    assert_eq!(ppu.read(0xFF41) & LYC_EQ_LY, LYC_EQ_LY);
+    // The frame still ends after the full 114 M-cycles of line 153, without another LYC interrupt.
+    interrupts.intr_flags = 0;
+    for _ in 0..112 {
+      assert!(!ppu.emulate_cycle(&mut interrupts));
+    }
+    assert!(ppu.emulate_cycle(&mut interrupts));
+    assert_eq!(ppu.mode(), PpuMode::OamScan);
+    assert_eq!(interrupts.intr_flags, 0);
+  }
+  #[test]
+  fn dma_register_reads_back() {
+    let mut ppu = Ppu::new(false);
+    assert_eq!(ppu.read(0xFF46), 0xFF);
+    ppu.write(0xFF46, 0xC1);
+    assert_eq!(ppu.read(0xFF46), 0xC1);
+  }
+  #[test]
+  fn stat_mode_bits_read_0_with_the_lcd_off() {
+    let mut ppu = enabled_ppu(false, 0);
+    ppu.stat = 0x40;
+    ppu.mode = Mode::Drawing;
+    assert_eq!(ppu.read(0xFF41), 0xC3);
+    ppu.lcdc = 0;
+    assert_eq!(ppu.read(0xFF41), 0xC0);
+  }
+  #[test]
+  fn unused_bits_read_as_1() {
+    for is_cgb in [false, true] {
+      let mut ppu = Ppu::new(is_cgb);
+      for addr in 0xFF51..=0xFF54 {
+        ppu.write(addr, 0x00);
+        assert_eq!(ppu.read(addr), 0xFF);
+      }
+      ppu.write(0xFF68, 0x85);
+      ppu.write(0xFF6A, 0x02);
+      if is_cgb {
+        assert_eq!((ppu.read(0xFF68), ppu.read(0xFF6A)), (0xC5, 0x42));
+      } else {
+        assert_eq!((ppu.read(0xFF68), ppu.read(0xFF6A)), (0xFF, 0xFF));
+      }
+    }
+  }
+  #[test]
+  fn tall_sprites_flip_over_both_tiles() {
+    let mut ppu = enabled_ppu(false, SPRITE_ENABLE | SPRITE_SIZE);
+    // Each row of tiles 2 and 3 stores its row number within the sprite in the low bits.
+    for row in 0..16 {
+      ppu.vram[(2 << 4) + (row << 1)] = row as u8;
+      ppu.vram[(2 << 4) + (row << 1) + 1] = 0xFF;
+    }
+    // Bit 0 of the tile index is ignored.
+    for (flags, flipped) in [(0, false), (Y_FLIP, true)] {
+      set_sprite(&mut ppu, 0, 16, 8, 3, flags);
+      for ly in 0..16 {
+        draw_line(&mut ppu, ly);
+        let row = line(&ppu, ly)[..8].iter().fold(0, |acc, &shade| acc << 1 | (shade & 1));
+        assert_eq!(row, if flipped { 15 - ly } else { ly }, "LY={} flags={:02x}", ly, flags);
+      }
+    }
+  }
+  // Runs until the next frame starts.
+  fn run_frame(ppu: &mut Ppu) {
+    let mut interrupts = Interrupts::default();
+    for _ in 0..2 * 154 * 114 {
+      if ppu.emulate_cycle(&mut interrupts) {
+        return;
+      }
+    }
+    panic!("no frame was completed");
+  }
+
+  #[test]
+  fn first_frame_after_turning_the_lcd_on_is_blank() {
+    let lcdc = PPU_ENABLE | BG_WINDOW_ENABLE | TILE_DATA_ADDRESSING_MODE;
+    let mut ppu = enabled_ppu(false, lcdc);
+    set_tile(&mut ppu, 0, 0xFF, 0xFF);
+    run_frame(&mut ppu);
+    run_frame(&mut ppu);
+    assert!(ppu.index_buffer.iter().all(|&shade| shade == 3));
+    ppu.write(0xFF40, lcdc & !PPU_ENABLE);
+    assert_eq!((ppu.read(0xFF44), ppu.read(0xFF41) & 0b11), (0, 0));
+    assert!(ppu.index_buffer.iter().all(|&shade| shade == 0));
+    ppu.write(0xFF40, lcdc);
+    run_frame(&mut ppu);
+    assert!(ppu.index_buffer.iter().all(|&shade| shade == 0));
+    run_frame(&mut ppu);
+    assert!(ppu.index_buffer.iter().all(|&shade| shade == 3));
+  }
+  #[test]
+  fn front_buffer_holds_the_last_frame_until_vblank() {
+    let mut ppu = enabled_ppu(false, BG_WINDOW_ENABLE | TILE_DATA_ADDRESSING_MODE);
+    set_tile(&mut ppu, 0, 0xFF, 0xFF);
+    run_frame(&mut ppu);
+    run_frame(&mut ppu);
+    let black = ppu.front_buffer().to_vec();
+    let hash = ppu.frame_hash();
+    set_tile(&mut ppu, 0, 0x00, 0x00);
+    let mut interrupts = Interrupts::default();
+    for _ in 0..72 * 114 {
+      ppu.emulate_cycle(&mut interrupts);
+    }
+    assert_ne!(ppu.buffer, black);
+    assert_eq!(ppu.front_buffer(), black);
+    assert_eq!(ppu.frame_hash(), hash);
+    run_frame(&mut ppu);
+    assert_ne!(ppu.frame_hash(), hash);
+  }
+  #[test]
+  fn lcdc_changes_apply_from_the_next_line() {
+    let lcdc = BG_WINDOW_ENABLE | TILE_DATA_ADDRESSING_MODE;
+    let mut ppu = enabled_ppu(false, lcdc);
+    set_tile(&mut ppu, 0, 0xFF, 0xFF);
+    draw_line(&mut ppu, 0);
+    ppu.write(0xFF40, PPU_ENABLE | TILE_DATA_ADDRESSING_MODE);
+    draw_line(&mut ppu, 1);
+    assert_eq!(line(&ppu, 0), [3; LCD_WIDTH]);
+    assert_eq!(line(&ppu, 1), [0; LCD_WIDTH]);
+  }
+  #[test]
+  fn oam_is_blocked_during_dma_in_hblank() {
+    let mut ppu = enabled_ppu(false, 0);
+    ppu.mode = Mode::HBlank;
+    ppu.write(0xFE00, 0x42);
+    ppu.write(0xFF46, 0xC0);
+    assert_eq!(ppu.read(0xFE00), 0xFF);
+    ppu.write(0xFE00, 0x24);
+    ppu.oam_dma = None;
+    assert_eq!(ppu.read(0xFE00), 0x42);
+  }
+  #[test]
+  fn palette_index_advances_on_blocked_writes() {
+    for (index, data) in [(0xFF68, 0xFF69), (0xFF6A, 0xFF6B)] {
+      let mut ppu = enabled_ppu(true, 0);
+      ppu.write(index, 0x80);
+      let before = ppu.read(data);
+      ppu.mode = Mode::Drawing;
+      ppu.write(data, 0x12);
+      assert_eq!(ppu.read(index), 0xC1);
+      ppu.mode = Mode::HBlank;
+      ppu.write(data, 0x34);
+      ppu.write(index, 0x00);
+      assert_eq!(ppu.read(data), before);
+      ppu.write(index, 0x01);
+      assert_eq!(ppu.read(data), 0x34);
+    }
+  }
+  #[test]
+  fn line_sprites_after_oam_scan() {
+    let mut ppu = enabled_ppu(false, SPRITE_ENABLE);
+    set_sprite(&mut ppu, 0, 16, 8, 0, 0);
+    // Above the line, except for 8x16 sprites.
+    set_sprite(&mut ppu, 1, 8, 8, 0, 0);
+    set_sprite(&mut ppu, 2, 21, 8, 0, 0);
+    for i in 3..15 {
+      set_sprite(&mut ppu, i, 16, 8, 0, 0);
+    }
+    draw_line(&mut ppu, 0);
+    assert_eq!(ppu.line_sprites(), [0, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+    draw_line(&mut ppu, 5);
+    assert_eq!(ppu.line_sprites(), [0, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+    draw_line(&mut ppu, 8);
+    assert_eq!(ppu.line_sprites(), [2]);
+    ppu.lcdc |= SPRITE_SIZE;
+    draw_line(&mut ppu, 0);
+    assert_eq!(ppu.line_sprites(), [0, 1, 3, 4, 5, 6, 7, 8, 9, 10]);
+  }
+  #[test]
+  fn color_correction_of_pure_red() {
+    let mut ppu = Ppu::new(true);
+    ppu.bg_palette_memory[0] = 0x1F;
+    ppu.bg_palette_memory[1] = 0x00;
+    assert_eq!(ppu.get_color_from_palette_memory(0, 0, false), [0xFF, 0x00, 0x00, 0xFF]);
+    ppu.set_color_correction(true);
+    assert_eq!(ppu.get_color_from_palette_memory(0, 0, false), [201, 0, 46, 0xFF]);
+  }
+  #[test]
+  fn hblank_callback_once_per_visible_line() {
+    let mut ppu = enabled_ppu(false, BG_WINDOW_ENABLE);
+    run_frame(&mut ppu);
+    let lines = Rc::new(std::cell::RefCell::new(Vec::new()));
+    let lines_clone = lines.clone();
+    ppu.set_hblank_callback(Rc::new(move |ly, _, _| lines_clone.borrow_mut().push(ly)));
+    run_frame(&mut ppu);
+    assert_eq!(*lines.borrow(), (0..144).collect::<Vec<_>>());
+  }
+  #[test]
+  fn dot_counts_up_and_wraps_at_the_end_of_each_line() {
+    let mut ppu = enabled_ppu(false, BG_WINDOW_ENABLE);
+    run_frame(&mut ppu);
+    let mut interrupts = Interrupts::default();
+    let mut lines = 0;
+    let mut dot = ppu.dot();
+    assert_eq!(dot, 0);
+    for _ in 0..154 * 114 {
+      ppu.emulate_cycle(&mut interrupts);
+      assert_eq!(ppu.dot(), (dot + 4) % 456);
+      dot = ppu.dot();
+      lines += (dot == 0) as usize;
+    }
+    assert_eq!(lines, 154);
+  }
+  #[test]
+  fn window_starts_at_wx_minus_7() {
+    for wx in [0, 1, 6, 7, 166, 167] {
+      let lcdc = BG_WINDOW_ENABLE | TILE_DATA_ADDRESSING_MODE | WINDOW_ENABLE | WINDOW_TILE_MAP;
+      let mut ppu = enabled_ppu(false, lcdc);
+      // The background is tile 0, which is blank. Window tiles are shade 3 in their first column and 1 elsewhere.
+      set_tile(&mut ppu, 1, 0xFF, 0x80);
+      ppu.vram[0x1C00..0x2000].fill(1);
+      ppu.wx = wx;
+      draw_line(&mut ppu, 0);
+      let expected: Vec<u8> = (0..LCD_WIDTH).map(|i| match (i + 7).checked_sub(wx as usize) {
+        None                  => 0,
+        Some(x) if x % 8 == 0 => 3,
+        Some(_)               => 1,
+      }).collect();
+      assert_eq!(line(&ppu, 0), expected, "WX={}", wx);
+    }
+  }
+}
@@ -0,0 +1,234 @@
+// https://gbdev.io/pandocs/Gameboy_Printer.html
+use crate::serial::SerialDevice;
+
+const INIT: u8 = 0x01;
+const PRINT: u8 = 0x02;
+const DATA: u8 = 0x04;
+
+// The printer is 20 tiles wide.
+pub const PRINTER_WIDTH: usize = 160;
+
+type Output = Box<dyn Fn(&[u8], usize)>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+  Magic1,
+  Magic2,
+  Command,
+  Compression,
+  LengthLow,
+  LengthHigh,
+  Data,
+  ChecksumLow,
+  ChecksumHigh,
+  Alive,
+  Status,
+}
+
+pub struct Printer {
+  state: State,
+  command: u8,
+  compressed: bool,
+  length: usize,
+  packet: Vec<u8>,
+  checksum: u16,
+  status: u8,
+  tiles: Vec<u8>,
+  output: Option<Output>,
+}
+
+impl Printer {
+  pub fn new() -> Self {
+    Self {
+      state: State::Magic1,
+      command: 0,
+      compressed: false,
+      length: 0,
+      packet: vec![],
+      checksum: 0,
+      status: 0,
+      tiles: vec![],
+      output: None,
+    }
+  }
+  // Receives the printed image as 0-3 shades, `PRINTER_WIDTH` pixels per row.
+  pub fn set_output(&mut self, output: Output) {
+    self.output = Some(output);
+  }
+  fn decompress(&self) -> Vec<u8> {
+    if !self.compressed {
+      return self.packet.clone();
+    }
+    let mut ret = vec![];
+    let mut i = 0;
+    while i < self.packet.len() {
+      let b = self.packet[i];
+      if b & 0x80 > 0 {
+        // A run of the next byte.
+        if let Some(&v) = self.packet.get(i + 1) {
+          ret.resize(ret.len() + (b & 0x7F) as usize + 2, v);
+        }
+        i += 2;
+      } else {
+        let end = std::cmp::min(i + 1 + b as usize + 1, self.packet.len());
+        ret.extend_from_slice(&self.packet[i + 1..end]);
+        i = end;
+      }
+    }
+    ret
+  }
+  fn print(&mut self, palette: u8) {
+    let tiles = std::mem::take(&mut self.tiles);
+    let Some(output) = self.output.as_ref() else { return };
+    let rows = tiles.len() / 16 / 20 * 8;
+    let mut pixels = vec![0; PRINTER_WIDTH * rows];
+    for (t, tile) in tiles.chunks_exact(16).enumerate() {
+      let (tx, ty) = (t % 20, t / 20);
+      for row in 0..8 {
+        let y = ty * 8 + row;
+        if y >= rows {
+          break;
+        }
+        for col in 0..8 {
+          let color = ((tile[row * 2] >> (7 - col)) & 1) | (((tile[row * 2 + 1] >> (7 - col)) & 1) << 1);
+          pixels[y * PRINTER_WIDTH + tx * 8 + col] = (palette >> (color << 1)) & 0b11;
+        }
+      }
+    }
+    output(&pixels, PRINTER_WIDTH);
+  }
+  fn execute(&mut self) {
+    match self.command {
+      INIT  => {
+        self.tiles.clear();
+        self.status = 0;
+      },
+      // The print parameters are sheets, margins, palette and exposure.
+      PRINT => if let Some(&palette) = self.packet.get(2) {
+        // A palette of 0 is treated as the default one.
+        self.print(if palette == 0 { 0xE4 } else { palette });
+      },
+      DATA  => {
+        let data = self.decompress();
+        self.tiles.extend_from_slice(&data);
+      },
+      _     => {},
+    }
+  }
+}
+
+impl Default for Printer {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl SerialDevice for Printer {
+  fn transfer(&mut self, byte: u8) -> u8 {
+    let mut ret = 0x00;
+    self.state = match self.state {
+      State::Magic1       => if byte == 0x88 { State::Magic2 } else { State::Magic1 },
+      State::Magic2       => if byte == 0x33 { State::Command } else { State::Magic1 },
+      State::Command      => {
+        self.command = byte;
+        self.checksum = byte as u16;
+        State::Compression
+      },
+      State::Compression  => {
+        self.compressed = byte & 1 > 0;
+        self.checksum = self.checksum.wrapping_add(byte as u16);
+        State::LengthLow
+      },
+      State::LengthLow    => {
+        self.length = byte as usize;
+        self.checksum = self.checksum.wrapping_add(byte as u16);
+        State::LengthHigh
+      },
+      State::LengthHigh   => {
+        self.length |= (byte as usize) << 8;
+        self.checksum = self.checksum.wrapping_add(byte as u16);
+        self.packet.clear();
+        if self.length > 0 { State::Data } else { State::ChecksumLow }
+      },
+      State::Data         => {
+        self.packet.push(byte);
+        self.checksum = self.checksum.wrapping_add(byte as u16);
+        if self.packet.len() < self.length { State::Data } else { State::ChecksumLow }
+      },
+      State::ChecksumLow  => {
+        self.checksum ^= byte as u16;
+        State::ChecksumHigh
+      },
+      State::ChecksumHigh => {
+        self.checksum ^= (byte as u16) << 8;
+        State::Alive
+      },
+      State::Alive        => {
+        ret = 0x81;
+        State::Status
+      },
+      State::Status       => {
+        if self.checksum == 0 {
+          self.status &= !0x01;
+          self.execute();
+        } else {
+          self.status |= 0x01;
+        }
+        // Bit 3: there is unprinted data.
+        ret = self.status | if self.tiles.is_empty() { 0 } else { 0x08 };
+        State::Magic1
+      },
+    };
+    ret
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{cell::RefCell, rc::Rc};
+
+  use super::*;
+
+  // Sends one packet and returns the status byte. `checksum_error` is XORed into the checksum.
+  fn send(printer: &mut Printer, command: u8, data: &[u8], checksum_error: u16) -> u8 {
+    let header = [command, 0x00, data.len() as u8, (data.len() >> 8) as u8];
+    let checksum = header.iter().chain(data).fold(0u16, |acc, &b| acc.wrapping_add(b as u16)) ^ checksum_error;
+    for &byte in [0x88, 0x33].iter().chain(&header).chain(data).chain(&checksum.to_le_bytes()) {
+      assert_eq!(printer.transfer(byte), 0x00);
+    }
+    assert_eq!(printer.transfer(0x00), 0x81);
+    printer.transfer(0x00)
+  }
+
+  #[test]
+  fn prints_a_row_of_tiles() {
+    let mut printer = Printer::new();
+    let printed = Rc::new(RefCell::new(None));
+    let printed_clone = printed.clone();
+    printer.set_output(Box::new(move |pixels, width| *printed_clone.borrow_mut() = Some((pixels.to_vec(), width))));
+
+    assert_eq!(send(&mut printer, INIT, &[], 0), 0x00);
+    // The first of 20 tiles is black, the rest white.
+    let mut tiles = vec![0x00; 20 * 16];
+    tiles[..16].fill(0xFF);
+    assert_eq!(send(&mut printer, DATA, &tiles, 0), 0x08);
+    assert_eq!(send(&mut printer, PRINT, &[0x01, 0x13, 0xE4, 0x40], 0), 0x00);
+
+    let (pixels, width) = printed.borrow_mut().take().unwrap();
+    assert_eq!(width, PRINTER_WIDTH);
+    assert_eq!(pixels.len(), PRINTER_WIDTH * 8);
+    for row in pixels.chunks_exact(PRINTER_WIDTH) {
+      assert_eq!(row[..8], [3; 8]);
+      assert!(row[8..].iter().all(|&p| p == 0));
+    }
+  }
+
+  #[test]
+  fn bad_checksum_sets_status_bit_0() {
+    let mut printer = Printer::new();
+    assert_eq!(send(&mut printer, DATA, &[0xFF; 16], 0x0100), 0x01);
+    // The packet was dropped.
+    assert!(printer.tiles.is_empty());
+    assert_eq!(send(&mut printer, INIT, &[], 0), 0x00);
+  }
+}
@@ -1,15 +1,33 @@
+use std::collections::VecDeque;
+
 use serde::{Deserialize, Serialize};
 
 use crate::cpu::interrupts::{Interrupts, self};
 
+// M-cycles per bit for the 8192 Hz internal clock, and for the 262144 Hz CGB fast clock.
+const NORMAL_BIT_CYCLES: usize = 128;
+const FAST_BIT_CYCLES: usize = 4;
+
+// Bytes kept for `take_output`. The oldest ones are dropped when nobody takes them.
+const OUTPUT_CAPACITY: usize = 0x1000;
+
+// A peripheral on the other end of the link cable. It receives each byte sent by
+// the Game Boy and returns the byte shifted back in.
+pub trait SerialDevice {
+  fn transfer(&mut self, byte: u8) -> u8;
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Serial {
   pub data: u8,
   control: u8,
   transfer_cnt: usize,
+  bits: u8,
   send_data: Option<u8>,
   recv_data: Option<u8>,
   is_cgb: bool,
+  #[serde(skip)]
+  output: VecDeque<u8>,
 }
 
 impl Serial {
@@ -18,15 +36,21 @@ impl Serial {
       data: 0,
       control: 0,
       transfer_cnt: 0,
+      bits: 0,
       send_data: None,
       recv_data: None,
       is_cgb,
+      output: VecDeque::new(),
     }
   }
+  pub fn enter_dmg_compat(&mut self) {
+    self.is_cgb = false;
+  }
   pub fn read(&self, addr: u16) -> u8 {
     match addr {
       0xFF01 => self.data,
-      0xFF02 => self.control,
+      // Bits 1-6 are unused, except for the clock speed bit 1 on CGB.
+      0xFF02 => self.control | if self.is_cgb { 0x7C } else { 0x7E },
       _      => unreachable!(),
     }
   }
@@ -35,47 +59,147 @@ impl Serial {
       0xFF01 => self.data = val,
       0xFF02 => {
         self.control = val;
+        // With the external clock selected, the transfer is held until the peer drives it via `recv`,
+        // so an internal transfer still in progress must not complete and raise SERIAL on its own.
+        if self.control & 0x81 == 0x80 && self.bits > 0 {
+          self.bits = 0;
+          self.send_data = None;
+        }
+        // A byte no peer has picked up is dropped, as there is nobody on the other end.
         if self.control & 0x81 == 0x81 {
-          if self.send_data.is_some() {
-            panic!("Now sending!!");
-          }
-          if self.control & 0b10 > 0 && self.is_cgb {
-            self.transfer_cnt = 4;
-          } else {
-            self.transfer_cnt = 128;
-          }
+          self.transfer_cnt = self.bit_cycles();
+          self.bits = 8;
           self.send_data = Some(self.data);
         }
       }
       _      => unreachable!(),
     }
   }
+  fn bit_cycles(&self) -> usize {
+    if self.control & 0b10 > 0 && self.is_cgb {
+      FAST_BIT_CYCLES
+    } else {
+      NORMAL_BIT_CYCLES
+    }
+  }
   pub fn emulate_cycle(&mut self, interrupts: &mut Interrupts) {
-    if self.recv_data.is_some() {
-      self.data = self.recv_data.take().unwrap();
-      self.control &= 0x7F;
-      interrupts.irq(interrupts::SERIAL);
+    if let Some(val) = self.recv_data.take() {
+      self.data = val;
+      if self.control & 0x80 > 0 {
+        self.control &= 0x7F;
+        interrupts.irq(interrupts::SERIAL);
+      }
     }
+    if self.bits == 0 {
+      return;
+    }
+    self.transfer_cnt -= 1;
     if self.transfer_cnt == 0 {
-      if self.send_data.is_some() {
+      self.bits -= 1;
+      if self.bits == 0 {
         self.control &= 0x7F;
         interrupts.irq(interrupts::SERIAL);
+        if let Some(val) = self.send_data {
+          if self.output.len() == OUTPUT_CAPACITY {
+            self.output.pop_front();
+          }
+          self.output.push_back(val);
+        }
+      } else {
+        self.transfer_cnt = self.bit_cycles();
       }
-    } else {
-      self.transfer_cnt -= 1;
     }
   }
   pub fn send(&mut self) -> Option<u8> {
-    if self.transfer_cnt == 0 && self.send_data.is_some() {
+    if self.bits == 0 && self.send_data.is_some() {
       self.send_data.take()
     } else {
       None
     }
   }
+  // Every byte sent with the internal clock since the last call, whether a peer took it or not.
+  // Test ROMs such as Blargg's print their results this way.
+  pub fn take_output(&mut self) -> Vec<u8> {
+    self.output.drain(..).collect()
+  }
   pub fn recv(&mut self, val: u8) {
     if self.recv_data.is_some() {
       panic!("Now sending!!");
     }
     self.recv_data = Some(val);
   }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Runs until SERIAL is requested, returning the number of M-cycles taken.
+  fn cycles_until_irq(serial: &mut Serial, interrupts: &mut Interrupts, max: usize) -> Option<usize> {
+    for i in 1..=max {
+      serial.emulate_cycle(interrupts);
+      if interrupts.intr_flags & interrupts::SERIAL > 0 {
+        return Some(i);
+      }
+    }
+    None
+  }
+
+  #[test]
+  fn internal_clock() {
+    let mut serial = Serial::new(false);
+    let mut interrupts = Interrupts::default();
+    serial.write(0xFF01, 0x42);
+    serial.write(0xFF02, 0x81);
+    assert_eq!(cycles_until_irq(&mut serial, &mut interrupts, 0x1000), Some(8 * NORMAL_BIT_CYCLES));
+    assert_eq!(serial.read(0xFF02) & 0x80, 0);
+    assert_eq!(serial.send(), Some(0x42));
+  }
+
+  #[test]
+  fn internal_fast_clock_on_cgb() {
+    let mut serial = Serial::new(true);
+    let mut interrupts = Interrupts::default();
+    serial.write(0xFF02, 0x83);
+    assert_eq!(cycles_until_irq(&mut serial, &mut interrupts, 0x1000), Some(8 * FAST_BIT_CYCLES));
+    // The speed bit doesn't exist on DMG.
+    let mut serial = Serial::new(false);
+    let mut interrupts = Interrupts::default();
+    serial.write(0xFF02, 0x83);
+    assert_eq!(cycles_until_irq(&mut serial, &mut interrupts, 0x1000), Some(8 * NORMAL_BIT_CYCLES));
+  }
+
+  #[test]
+  fn external_clock_waits_for_the_peer() {
+    let mut serial = Serial::new(false);
+    let mut interrupts = Interrupts::default();
+    serial.write(0xFF01, 0x42);
+    serial.write(0xFF02, 0x80);
+    assert_eq!(cycles_until_irq(&mut serial, &mut interrupts, 0x1000), None);
+    assert_eq!(serial.send(), None);
+    serial.recv(0x99);
+    assert_eq!(cycles_until_irq(&mut serial, &mut interrupts, 1), Some(1));
+    assert_eq!(serial.read(0xFF01), 0x99);
+    assert_eq!(serial.read(0xFF02) & 0x80, 0);
+  }
+  #[test]
+  fn output_is_taken_once_in_order() {
+    let mut serial = Serial::new(false);
+    for &byte in b"OK" {
+      let mut interrupts = Interrupts::default();
+      serial.write(0xFF01, byte);
+      serial.write(0xFF02, 0x81);
+      assert!(cycles_until_irq(&mut serial, &mut interrupts, 0x1000).is_some());
+    }
+    assert_eq!(serial.take_output(), b"OK");
+    assert_eq!(serial.take_output(), b"");
+  }
+  #[test]
+  fn unused_control_bits_read_as_1() {
+    let mut serial = Serial::new(false);
+    serial.write(0xFF02, 0x01);
+    assert_eq!(serial.read(0xFF02), 0x7F);
+    let mut serial = Serial::new(true);
+    serial.write(0xFF02, 0x01);
+    assert_eq!(serial.read(0xFF02), 0x7D);
+  }
+}
@@ -0,0 +1,148 @@
+// A flat 64 KiB memory in place of the whole memory map, for running CPU instructions
+// in isolation, e.g. the SM83 single-step tests.
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+  bootrom::Bootrom,
+  cartridge::Cartridge,
+  peripherals::{BusDevice, Peripherals},
+};
+
+// Clones share the memory, so that a test keeps access to what the CPU sees through `Peripherals`.
+#[derive(Clone)]
+pub struct TestBus {
+  pub mem: Rc<RefCell<Box<[u8; 0x10000]>>>,
+}
+
+impl TestBus {
+  pub fn new() -> Self {
+    Self {
+      mem: Rc::new(RefCell::new(Box::new([0; 0x10000]))),
+    }
+  }
+  // Peripherals with every address claimed by `bus`. Only the CPU should be run on them,
+  // as the PPU, timer and the rest never see the bus traffic.
+  pub fn peripherals(bus: TestBus) -> Peripherals {
+    // A blank ROM ONLY cartridge, which is never reached.
+    let mut rom = vec![0; 0x8000];
+    rom[0x14D] = rom[0x134..0x14D].iter().fold(0u8, |acc, &b| acc.wrapping_sub(b).wrapping_sub(1));
+    let cartridge = Cartridge::new(rom, None).unwrap();
+    let mut peripherals = Peripherals::new(Bootrom::new(), cartridge, true);
+    peripherals.add_device(Box::new(bus));
+    peripherals
+  }
+}
+
+impl Default for TestBus {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl BusDevice for TestBus {
+  fn read(&self, addr: u16) -> Option<u8> {
+    Some(self.mem.borrow()[addr as usize])
+  }
+  fn write(&mut self, addr: u16, val: u8) -> bool {
+    self.mem.borrow_mut()[addr as usize] = val;
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde::Deserialize;
+
+  use crate::{
+    cpu::{Cpu, Registers},
+    test_bus::TestBus,
+  };
+
+  // Cases in the format of the SM83 single-step tests. As there, `pc` is one past the opcode,
+  // which has already been fetched, and `cycles` lists the bus activity of each M-cycle.
+  const CASES: &str = r#"[
+    {
+      "name": "00 0000",
+      "initial": { "pc": 257, "sp": 0, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ie": 0, "ram": [[256, 0]] },
+      "final":   { "pc": 258, "sp": 0, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ie": 0, "ram": [[256, 0]] },
+      "cycles": [[257, 0, "r-m"]]
+    },
+    {
+      "name": "3c 0000",
+      "initial": { "pc": 257, "sp": 0, "a": 255, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ie": 0, "ram": [[256, 60]] },
+      "final":   { "pc": 258, "sp": 0, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 160, "h": 0, "l": 0, "ime": 0, "ie": 0, "ram": [[256, 60]] },
+      "cycles": [[257, 0, "r-m"]]
+    },
+    {
+      "name": "ea 0000",
+      "initial": { "pc": 257, "sp": 0, "a": 90, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ie": 0, "ram": [[256, 234], [257, 0], [258, 192]] },
+      "final":   { "pc": 260, "sp": 0, "a": 90, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ie": 0, "ram": [[256, 234], [257, 0], [258, 192], [49152, 90]] },
+      "cycles": [[257, 0, "r-m"], [258, 192, "r-m"], [49152, 90, "-wm"], [259, 0, "r-m"]]
+    },
+    {
+      "name": "c5 0000",
+      "initial": { "pc": 257, "sp": 53248, "a": 0, "b": 18, "c": 52, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ie": 0, "ram": [[256, 197]] },
+      "final":   { "pc": 258, "sp": 53246, "a": 0, "b": 18, "c": 52, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ie": 0, "ram": [[256, 197], [53246, 52], [53247, 18]] },
+      "cycles": [null, [53247, 18, "-wm"], [53246, 52, "-wm"], [257, 0, "r-m"]]
+    },
+    {
+      "name": "cb 11 0000",
+      "initial": { "pc": 257, "sp": 0, "a": 0, "b": 0, "c": 128, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ie": 0, "ram": [[256, 203], [257, 17]] },
+      "final":   { "pc": 259, "sp": 0, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 144, "h": 0, "l": 0, "ime": 0, "ie": 0, "ram": [[256, 203], [257, 17]] },
+      "cycles": [[257, 17, "r-m"], [258, 0, "r-m"]]
+    },
+    {
+      "name": "e8 0000",
+      "initial": { "pc": 257, "sp": 65528, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0, "l": 0, "ime": 0, "ie": 0, "ram": [[256, 232], [257, 8]] },
+      "final":   { "pc": 259, "sp": 0, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 48, "h": 0, "l": 0, "ime": 0, "ie": 0, "ram": [[256, 232], [257, 8]] },
+      "cycles": [[257, 8, "r-m"], null, null, [258, 0, "r-m"]]
+    }
+  ]"#;
+
+  #[derive(Deserialize)]
+  struct Case {
+    name: String,
+    initial: State,
+    #[serde(rename = "final")]
+    expected: State,
+    cycles: Vec<serde_json::Value>,
+  }
+
+  #[derive(Deserialize)]
+  struct State {
+    #[serde(flatten)]
+    regs: Registers,
+    ime: u8,
+    ram: Vec<(u16, u8)>,
+  }
+
+  #[test]
+  fn single_step_cases() {
+    let cases: Vec<Case> = serde_json::from_str(CASES).unwrap();
+    for case in cases {
+      let bus = TestBus::new();
+      let mut peripherals = TestBus::peripherals(bus.clone());
+      for &(addr, val) in &case.initial.ram {
+        bus.mem.borrow_mut()[addr as usize] = val;
+      }
+      let mut cpu = Cpu::new();
+      cpu.interrupts.ime = case.initial.ime > 0;
+      let pc = case.initial.regs.pc.wrapping_sub(1);
+      cpu.set_state(&peripherals, Registers { pc, ..case.initial.regs });
+
+      let mut cycles = 0;
+      while cycles == 0 || !cpu.at_instruction_boundary() {
+        cpu.emulate_cycle(&mut peripherals);
+        cycles += 1;
+      }
+      let mut regs = cpu.registers();
+      regs.pc = regs.pc.wrapping_add(1);
+      assert_eq!(format!("{:?}", regs), format!("{:?}", case.expected.regs), "{}", case.name);
+      assert_eq!(cpu.interrupts.ime, case.expected.ime > 0, "{}", case.name);
+      for &(addr, val) in &case.expected.ram {
+        assert_eq!(bus.mem.borrow()[addr as usize], val, "{} at {:04x}", case.name, addr);
+      }
+      assert_eq!(cycles, case.cycles.len(), "{}", case.name);
+    }
+  }
+}
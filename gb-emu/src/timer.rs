@@ -14,22 +14,35 @@ pub struct Timer {
 impl Timer {
   pub fn emulate_cycle(&mut self, interrupts: &mut Interrupts) {
     self.div = self.div.wrapping_add(4);
-    let modulo: u16 = match self.tac & 0b11 {
-      0b01 => 16,
-      0b10 => 64,
-      0b11 => 256,
-      _    => 1024,
-    };
     if self.overflow {
       self.tima = self.tma;
       self.overflow = false;
       interrupts.irq(interrupts::TIMER);
-    } else if self.tac & 0b100 > 0 && self.div & (modulo - 1) == 0 {
-      let (tima, overflow) = self.tima.overflowing_add(1);
-      self.tima = tima;
-      self.overflow = overflow;
+    } else if self.tac & 0b100 > 0 && self.div & (self.modulo() - 1) == 0 {
+      self.increment();
+    }
+  }
+  fn modulo(&self) -> u16 {
+    match self.tac & 0b11 {
+      0b01 => 16,
+      0b10 => 64,
+      0b11 => 256,
+      _    => 1024,
     }
   }
+  // TIMA is clocked by the falling edge of the enabled, selected DIV bit.
+  fn signal(&self) -> bool {
+    self.tac & 0b100 > 0 && self.div & (self.modulo() >> 1) > 0
+  }
+  fn increment(&mut self) {
+    let (tima, overflow) = self.tima.overflowing_add(1);
+    self.tima = tima;
+    self.overflow = overflow;
+  }
+  // Sets the internal counter, of which DIV is the upper byte.
+  pub fn set_counter(&mut self, div: u16) {
+    self.div = div;
+  }
   pub fn read(&self, addr: u16) -> u8 {
     match addr {
       0xFF04 => (self.div >> 8) as u8,
@@ -40,6 +53,7 @@ impl Timer {
     }
   }
   pub fn write(&mut self, addr: u16, val: u8) {
+    let signal = self.signal();
     match addr {
       0xFF04 => self.div = 0,
       0xFF05 => if !self.overflow {
@@ -49,5 +63,68 @@ impl Timer {
       0xFF07 => self.tac = val & 0b111,
       _      => unreachable!(),
     }
+    // Resetting DIV or changing TAC can also cause a falling edge.
+    if signal && !self.signal() {
+      self.increment();
+    }
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A timer at 262144 Hz, whose TIMA is clocked by bit 3 of the counter.
+  fn fast_timer(counter: u16) -> Timer {
+    let mut timer = Timer::default();
+    timer.write(0xFF07, 0b101);
+    timer.set_counter(counter);
+    timer
+  }
+
+  #[test]
+  fn div_reset_clocks_tima_while_the_bit_is_set() {
+    let mut timer = fast_timer(0b1000);
+    timer.write(0xFF04, 0);
+    assert_eq!(timer.read(0xFF05), 1);
+    let mut timer = fast_timer(0b0100);
+    timer.write(0xFF04, 0);
+    assert_eq!(timer.read(0xFF05), 0);
+  }
+
+  #[test]
+  fn disabling_tac_clocks_tima_while_the_bit_is_set() {
+    let mut timer = fast_timer(0b1000);
+    timer.write(0xFF07, 0b001);
+    assert_eq!(timer.read(0xFF05), 1);
+    let mut timer = fast_timer(0b0000);
+    timer.write(0xFF07, 0b001);
+    assert_eq!(timer.read(0xFF05), 0);
+  }
+
+  #[test]
+  fn changing_frequency_clocks_tima_when_the_bit_falls() {
+    // Bit 3 is set but bit 9, used at 4096 Hz, isn't.
+    let mut timer = fast_timer(0b1000);
+    timer.write(0xFF07, 0b100);
+    assert_eq!(timer.read(0xFF05), 1);
+    // Both bits are set.
+    let mut timer = fast_timer(0b10_0000_1000);
+    timer.write(0xFF07, 0b100);
+    assert_eq!(timer.read(0xFF05), 0);
+  }
+
+  #[test]
+  fn overflow_reloads_tma_a_cycle_later() {
+    let mut timer = fast_timer(0);
+    let mut interrupts = Interrupts::default();
+    timer.write(0xFF05, 0xFF);
+    timer.write(0xFF06, 0x42);
+    for _ in 0..4 {
+      timer.emulate_cycle(&mut interrupts);
+    }
+    assert_eq!(timer.read(0xFF05), 0);
+    timer.emulate_cycle(&mut interrupts);
+    assert_eq!(timer.read(0xFF05), 0x42);
+  }
+}
@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+
+// The undocumented registers FF72-FF75 with no known function.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Undocumented {
+  is_cgb: bool,
+  regs: [u8; 4],
+}
+
+impl Undocumented {
+  pub fn new(is_cgb: bool) -> Self {
+    Self {
+      is_cgb,
+      regs: [0; 4],
+    }
+  }
+  pub fn enter_dmg_compat(&mut self) {
+    self.is_cgb = false;
+  }
+  pub fn read(&self, addr: u16) -> u8 {
+    let val = self.regs[(addr - 0xFF72) as usize];
+    match addr {
+      0xFF72 | 0xFF73 => val,
+      // FF74 only exists in CGB mode.
+      0xFF74          => if self.is_cgb { val } else { 0xFF },
+      // Only bits 4-6 are implemented.
+      0xFF75          => val | 0x8F,
+      _               => unreachable!(),
+    }
+  }
+  pub fn write(&mut self, addr: u16, val: u8) {
+    match addr {
+      0xFF72 | 0xFF73 => self.regs[(addr - 0xFF72) as usize] = val,
+      0xFF74          => if self.is_cgb {
+        self.regs[2] = val;
+      },
+      0xFF75          => self.regs[3] = val & 0x70,
+      _               => unreachable!(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn read_after_write() {
+    for is_cgb in [false, true] {
+      let mut regs = Undocumented::new(is_cgb);
+      for addr in 0xFF72..=0xFF75 {
+        regs.write(addr, 0x5A);
+      }
+      assert_eq!(regs.read(0xFF72), 0x5A);
+      assert_eq!(regs.read(0xFF73), 0x5A);
+      assert_eq!(regs.read(0xFF74), if is_cgb { 0x5A } else { 0xFF });
+      assert_eq!(regs.read(0xFF75), 0x5A | 0x8F);
+      regs.write(0xFF75, 0x00);
+      assert_eq!(regs.read(0xFF75), 0x8F);
+    }
+    // FF74 is locked once in DMG compatibility mode.
+    let mut regs = Undocumented::new(true);
+    regs.write(0xFF74, 0x12);
+    regs.enter_dmg_compat();
+    regs.write(0xFF74, 0x34);
+    assert_eq!(regs.read(0xFF74), 0xFF);
+  }
+}
@@ -17,9 +17,24 @@ impl WRam {
       ram: vec![0; 0x8000],
     }
   }
+  pub fn enter_dmg_compat(&mut self) {
+    self.is_cgb = false;
+  }
+  pub fn fill(&mut self, next: &mut impl FnMut() -> u8) {
+    self.ram.iter_mut().for_each(|b| *b = next());
+  }
+  // Bank n at n * 0x1000, so the first 0x2000 bytes are C000-DFFF with SVBK at 1. DMG only has those.
+  pub fn as_slice(&self) -> &[u8] {
+    if self.is_cgb { &self.ram } else { &self.ram[..0x2000] }
+  }
+  #[cfg(feature = "debug")]
+  pub fn as_mut_slice(&mut self) -> &mut [u8] {
+    if self.is_cgb { &mut self.ram } else { &mut self.ram[..0x2000] }
+  }
   pub fn read(&self, addr: u16) -> u8 {
     if addr == 0xFF70 {
-      return self.svbk;
+      // SVBK only exists on CGB, and its upper bits read as 1.
+      return if self.is_cgb { 0xF8 | self.svbk } else { 0xFF };
     }
     assert!(addr >= 0xC000 && addr <= 0xFDFF);
     if self.is_cgb {
@@ -34,7 +49,7 @@ impl WRam {
   }
   pub fn write(&mut self, addr: u16, val: u8) {
     if addr == 0xFF70 {
-      self.svbk = val;
+      self.svbk = val & 0x07;
       return;
     }
     assert!(addr >= 0xC000 && addr <= 0xFDFF);
@@ -48,4 +63,41 @@ impl WRam {
       self.ram[(addr as usize) & 0x1FFF] = val;
     }
   }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn svbk_selects_the_d000_bank() {
+    let mut wram = WRam::new(true);
+    wram.write(0xFF70, 0x02);
+    assert_eq!(wram.read(0xFF70), 0xFA);
+    wram.write(0xD000, 0x22);
+    assert_eq!(wram.as_slice()[0x2000], 0x22);
+    wram.write(0xFF70, 0x03);
+    assert_eq!(wram.read(0xD000), 0x00);
+    wram.write(0xFF70, 0x02);
+    assert_eq!(wram.read(0xD000), 0x22);
+    // Bank 0 selects bank 1.
+    wram.write(0xFF70, 0x00);
+    wram.write(0xD000, 0x11);
+    assert_eq!(wram.as_slice()[0x1000], 0x11);
+    // DMG has no SVBK.
+    let mut dmg = WRam::new(false);
+    dmg.write(0xFF70, 0x02);
+    assert_eq!(dmg.read(0xFF70), 0xFF);
+  }
+  #[test]
+  fn as_slice_covers_every_bank() {
+    let mut dmg = WRam::new(false);
+    dmg.write(0xDFFF, 0x44);
+    assert_eq!(dmg.as_slice().len(), 0x2000);
+    assert_eq!(dmg.as_slice()[0x1FFF], 0x44);
+    let mut cgb = WRam::new(true);
+    cgb.write(0xFF70, 0x07);
+    cgb.write(0xDFFF, 0x77);
+    assert_eq!(cgb.as_slice().len(), 0x8000);
+    assert_eq!(cgb.as_slice()[0x7FFF], 0x77);
+  }
+}